@@ -0,0 +1,94 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use super::lexicon::Lexicon;
+use super::titles::{ArticleId, TitleDict};
+
+// Letters tried when looking for "plus one letter" anagrams
+const EXTRA_LETTERS: &str = "abcdefghijklmnopqrstuvwxyz0123456789";
+
+/**
+ * Sorted letters of a word or title, ignoring case, spaces and punctuation,
+ * so "Dirty Room" and "dormitory" share a signature
+ */
+pub fn letter_signature(text: &str) -> String {
+    let mut letters: Vec<char> = text.to_lowercase().chars().filter(|c| c.is_alphanumeric()).collect();
+    letters.sort_unstable();
+    letters.into_iter().collect()
+}
+
+/**
+ * Signature -> every word or title with that multiset of letters
+ */
+pub struct AnagramIndex {
+    signatures: HashMap<String, Vec<ArticleId>>
+}
+
+impl AnagramIndex {
+    pub fn new(lexicons: &[Arc<Lexicon>], titles: &TitleDict) -> AnagramIndex {
+        let mut signatures: HashMap<String, Vec<ArticleId>> = HashMap::new();
+        let mut seen: HashSet<ArticleId> = HashSet::new();
+        for lexicon in lexicons {
+            for word in lexicon.words() {
+                if !seen.insert(*word) {
+                    continue;
+                }
+                let signature = letter_signature(&titles.resolve(*word));
+                if !signature.is_empty() {
+                    signatures.entry(signature).or_default().push(*word);
+                }
+            }
+        }
+        AnagramIndex{signatures}
+    }
+
+    pub fn lookup(&self, signature: &str) -> &[ArticleId] {
+        self.signatures.get(signature).map(|words| &words[..]).unwrap_or(&[])
+    }
+
+    /**
+     * Anagrams of text using letter_delta more (1) or fewer (-1) letters, or exactly the same (0)
+     */
+    pub fn search(&self, text: &str, letter_delta: i32) -> Vec<ArticleId> {
+        let signature: Vec<char> = letter_signature(text).chars().collect();
+        let mut candidates: HashSet<String> = HashSet::new();
+        match letter_delta {
+            0 => {
+                candidates.insert(signature.iter().collect());
+            },
+            1 => {
+                for extra in EXTRA_LETTERS.chars() {
+                    let mut letters = signature.clone();
+                    let position = letters.binary_search(&extra).unwrap_or_else(|position| position);
+                    letters.insert(position, extra);
+                    candidates.insert(letters.into_iter().collect());
+                }
+            },
+            -1 => {
+                for position in 0..signature.len() {
+                    // Removing either of a repeated letter gives the same signature
+                    if position > 0 && signature[position] == signature[position - 1] {
+                        continue;
+                    }
+                    let mut letters = signature.clone();
+                    letters.remove(position);
+                    candidates.insert(letters.into_iter().collect());
+                }
+            },
+            _ => {}
+        }
+        let mut results: Vec<ArticleId> = Vec::new();
+        for candidate in candidates {
+            results.extend_from_slice(self.lookup(&candidate));
+        }
+        results
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+}
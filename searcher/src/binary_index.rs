@@ -0,0 +1,501 @@
+extern crate fst;
+extern crate memmap;
+extern crate serde_json;
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+use memmap::Mmap;
+
+use fst::{Map, MapBuilder};
+use serde_json::Value;
+
+use super::indexer::Searchable;
+use super::manifest;
+use super::stemmer;
+use super::titles::{ArticleId, QueryTitles};
+
+/**
+ * Binary association file layout (all integers little endian):
+ *
+ * - header (HEADER_LEN bytes): magic, version, build parameters, counts, section positions and
+ *   the size and mtime of the source file it was built from
+ * - string offsets: (num_strings + 1) x u64 byte offsets into string data
+ * - string data: utf8 bytes of every key and interned article title
+ * - entry offsets: (num_entries + 1) x u64 offsets (in u32 units) into entry data
+ * - entry data: per original line, the key string id followed by its article string ids
+ * - stem offsets: (num_stems + 1) x u64 offsets (in u32 units) into stem data
+ * - stem data: entry ids for each stem, in stem order
+ * - fst: maps each stem to its ordinal in the stem offsets table
+ * - degree fst: maps each article title to the number of entries listing it
+ */
+const MAGIC: &[u8; 8] = b"ASSOCIDX";
+const VERSION: u32 = 3;
+const HEADER_LEN: usize = 144;
+// Marks a string whose shared TitleDict id isn't known (yet)
+const UNMAPPED: u32 = u32::MAX;
+
+pub struct BinaryIndex {
+    mmap: Arc<Mmap>,
+    stems: Map<MmapSection>,
+    degrees: Map<MmapSection>,
+    num_strings: usize,
+    num_entries: usize,
+    num_stems: usize,
+    string_offsets_pos: usize,
+    string_data_pos: usize,
+    entry_offsets_pos: usize,
+    entry_data_pos: usize,
+    stem_offsets_pos: usize,
+    stem_data_pos: usize,
+    // stem grouping the stems were generated with
+    max_group: usize,
+    include_whole: bool,
+    // size and mtime of the source file when it was built
+    source_size: u64,
+    source_mtime: u64,
+    // local string id -> shared ArticleId, filled in lazily as strings are hit by searches
+    article_ids: Vec<AtomicU32>
+}
+
+#[derive(Debug, Default)]
+pub struct BinaryStats {
+    pub strings: u64,
+    pub entries: u64,
+    pub stems: u64
+}
+
+// Lets the fst read its bytes straight out of the shared mmap
+struct MmapSection {
+    mmap: Arc<Mmap>,
+    start: usize,
+    end: usize
+}
+
+impl AsRef<[u8]> for MmapSection {
+    fn as_ref(&self) -> &[u8] {
+        &self.mmap[self.start..self.end]
+    }
+}
+
+// Temporary file for one section, appended to while streaming the association file
+struct SectionWriter {
+    path: String,
+    writer: BufWriter<File>,
+    len: u64
+}
+
+impl SectionWriter {
+    fn new(path: String) -> io::Result<SectionWriter> {
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok(SectionWriter{path, writer, len: 0})
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+
+    // Copies the section into the final file and removes the temporary file
+    fn append_to(mut self, output: &mut BufWriter<File>) -> io::Result<()> {
+        self.writer.flush()?;
+        drop(self.writer);
+        let mut section_file = File::open(&self.path)?;
+        io::copy(&mut section_file, output)?;
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap())
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/**
+ * Converts an association file into the binary format, the input has format:
+ * - ["text", ["a", "bunch", "of", "article", "titles", "containing", "text"]]
+ *
+ * Article titles are interned so each title is stored once.
+ */
+pub fn write_binary_index(file_path: &str, output_path: &str, max_group: usize, include_whole: bool) -> io::Result<BinaryStats> {
+    let process_start = Instant::now();
+    // Taken before reading, so a source changed while writing counts as stale
+    let (source_size, source_mtime) = manifest::source_stamp(file_path)?;
+    let mut string_offsets = SectionWriter::new(format!("{}.strofs.tmp", output_path))?;
+    let mut string_data = SectionWriter::new(format!("{}.strdata.tmp", output_path))?;
+    let mut entry_offsets = SectionWriter::new(format!("{}.entofs.tmp", output_path))?;
+    let mut entry_data = SectionWriter::new(format!("{}.entdata.tmp", output_path))?;
+
+    // Keys are already unique per line, only article titles repeat
+    let mut article_ids: HashMap<String, u32> = HashMap::new();
+    // string id -> number of entries listing it
+    let mut degrees: Vec<u32> = Vec::new();
+    let mut stem_map: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let mut num_strings: u32 = 0;
+    let mut num_entries: u32 = 0;
+    string_offsets.write(&0u64.to_le_bytes())?;
+    entry_offsets.write(&0u64.to_le_bytes())?;
+
+    let reader = io::BufReader::new(File::open(file_path)?);
+    for (line_num, line) in reader.lines().enumerate() {
+        let v: Value = serde_json::from_str(&line?)
+            .map_err(|e| invalid_data(format!("{}:{}: {}", file_path, line_num + 1, e)))?;
+        let (title, article_array) = match v.as_array() {
+            Some(pair) if pair.len() == 2 && pair[0].is_string() && pair[1].is_array() => {
+                (pair[0].as_str().unwrap(), pair[1].as_array().unwrap())
+            },
+            _ => return Err(invalid_data(format!("{}:{}: expected [\"text\", [articles]]", file_path, line_num + 1)))
+        };
+
+        let key_id = num_strings;
+        string_data.write(title.as_bytes())?;
+        string_offsets.write(&string_data.len.to_le_bytes())?;
+        num_strings += 1;
+        entry_data.write(&key_id.to_le_bytes())?;
+        for article in article_array {
+            let article_string = match article.as_str() {
+                Some(article_string) => article_string,
+                None => return Err(invalid_data(format!("{}:{}: article is not a string", file_path, line_num + 1)))
+            };
+            let article_id = match article_ids.get(article_string) {
+                Some(id) => *id,
+                None => {
+                    let id = num_strings;
+                    string_data.write(article_string.as_bytes())?;
+                    string_offsets.write(&string_data.len.to_le_bytes())?;
+                    num_strings += 1;
+                    article_ids.insert(article_string.to_string(), id);
+                    id
+                }
+            };
+            if degrees.len() <= article_id as usize {
+                degrees.resize(article_id as usize + 1, 0);
+            }
+            degrees[article_id as usize] += 1;
+            entry_data.write(&article_id.to_le_bytes())?;
+        }
+        entry_offsets.write(&(entry_data.len / 4).to_le_bytes())?;
+
+        for stem in stemmer::generate_stems(title, max_group, include_whole) {
+            stem_map.entry(stem).or_default().push(num_entries);
+        }
+        num_entries += 1;
+        if num_entries % 1000000 == 0 {
+            println!("counter: {}", num_entries);
+        }
+    }
+    println!("Finished gathering entries in: {} seconds", process_start.elapsed().as_secs());
+    let mut sorted_articles: Vec<(String, u32)> = article_ids.into_iter().collect();
+    sorted_articles.sort_unstable();
+    let mut degree_builder = MapBuilder::memory();
+    for (article, id) in sorted_articles {
+        degree_builder.insert(article, degrees[id as usize] as u64).map_err(|e| invalid_data(e.to_string()))?;
+    }
+    let degree_fst_bytes = degree_builder.into_inner().map_err(|e| invalid_data(e.to_string()))?;
+
+    let mut stem_offsets: Vec<u8> = Vec::with_capacity((stem_map.len() + 1) * 8);
+    let mut stem_data: Vec<u8> = Vec::new();
+    let mut fst_builder = MapBuilder::memory();
+    stem_offsets.extend_from_slice(&0u64.to_le_bytes());
+    for (ordinal, (stem, entries)) in stem_map.iter().enumerate() {
+        for entry in entries {
+            stem_data.extend_from_slice(&entry.to_le_bytes());
+        }
+        stem_offsets.extend_from_slice(&((stem_data.len() / 4) as u64).to_le_bytes());
+        fst_builder.insert(stem, ordinal as u64).map_err(|e| invalid_data(e.to_string()))?;
+    }
+    let fst_bytes = fst_builder.into_inner().map_err(|e| invalid_data(e.to_string()))?;
+
+    let string_offsets_pos = HEADER_LEN as u64;
+    let string_data_pos = string_offsets_pos + string_offsets.len;
+    let entry_offsets_pos = string_data_pos + string_data.len;
+    let entry_data_pos = entry_offsets_pos + entry_offsets.len;
+    let stem_offsets_pos = entry_data_pos + entry_data.len;
+    let stem_data_pos = stem_offsets_pos + stem_offsets.len() as u64;
+    let fst_pos = stem_data_pos + stem_data.len() as u64;
+    let degree_fst_pos = fst_pos + fst_bytes.len() as u64;
+
+    let mut header: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&(max_group as u32).to_le_bytes());
+    header.extend_from_slice(&(include_whole as u32).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    for value in &[num_strings as u64, num_entries as u64, stem_map.len() as u64,
+                   string_offsets_pos, string_data_pos, entry_offsets_pos, entry_data_pos,
+                   stem_offsets_pos, stem_data_pos, fst_pos, fst_bytes.len() as u64,
+                   degree_fst_pos, degree_fst_bytes.len() as u64, source_size, source_mtime] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+    assert_eq!(header.len(), HEADER_LEN);
+
+    // Written next to the output and renamed once complete, so an interrupted build never leaves a loadable file
+    let tmp_path = format!("{}.tmp", output_path);
+    let mut output = BufWriter::new(File::create(&tmp_path)?);
+    output.write_all(&header)?;
+    string_offsets.append_to(&mut output)?;
+    string_data.append_to(&mut output)?;
+    entry_offsets.append_to(&mut output)?;
+    entry_data.append_to(&mut output)?;
+    output.write_all(&stem_offsets)?;
+    output.write_all(&stem_data)?;
+    output.write_all(&fst_bytes)?;
+    output.write_all(&degree_fst_bytes)?;
+    output.flush()?;
+    drop(output);
+    fs::rename(&tmp_path, output_path)?;
+    println!("Finished writing binary index: {} seconds (cumulative)", process_start.elapsed().as_secs());
+    Ok(BinaryStats{strings: num_strings as u64, entries: num_entries as u64, stems: stem_map.len() as u64})
+}
+
+/**
+ * Checks every section of the header lies inside the file in layout order, that the offset
+ * tables hold one offset per item (plus the end), never go backwards and stay inside the data
+ * they point into, that every string is utf8 and that every id in the entry and stem data is
+ * in range. After this, lookups can slice the mmap without going out of bounds.
+ */
+fn check_layout(bytes: &[u8]) -> Result<(), String> {
+    let (num_strings, num_entries, num_stems) = (read_u64(bytes, 24), read_u64(bytes, 32), read_u64(bytes, 40));
+    let (string_offsets_pos, string_data_pos) = (read_u64(bytes, 48), read_u64(bytes, 56));
+    let (entry_offsets_pos, entry_data_pos) = (read_u64(bytes, 64), read_u64(bytes, 72));
+    let (stem_offsets_pos, stem_data_pos) = (read_u64(bytes, 80), read_u64(bytes, 88));
+    let (fst_pos, fst_len) = (read_u64(bytes, 96), read_u64(bytes, 104));
+    let (degree_fst_pos, degree_fst_len) = (read_u64(bytes, 112), read_u64(bytes, 120));
+    let fst_end = fst_pos.checked_add(fst_len).ok_or("fst length overflows")?;
+    let degree_fst_end = degree_fst_pos.checked_add(degree_fst_len).ok_or("degree fst length overflows")?;
+    let bounds = [HEADER_LEN as u64, string_offsets_pos, string_data_pos, entry_offsets_pos, entry_data_pos,
+                  stem_offsets_pos, stem_data_pos, fst_pos, fst_end, degree_fst_pos, degree_fst_end, bytes.len() as u64];
+    if bounds.windows(2).any(|pair| pair[0] > pair[1]) {
+        return Err(format!("sections out of order or past the end of the file ({} bytes)", bytes.len()));
+    }
+    // Ids are stored as u32
+    if num_strings > u32::MAX as u64 || num_entries > u32::MAX as u64 {
+        return Err(format!("{} strings and {} entries don't fit u32 ids", num_strings, num_entries));
+    }
+    // (name, table start, table end, items, data length, data unit size)
+    let tables = [
+        ("string", string_offsets_pos, string_data_pos, num_strings, entry_offsets_pos - string_data_pos, 1),
+        ("entry", entry_offsets_pos, entry_data_pos, num_entries, stem_offsets_pos - entry_data_pos, 4),
+        ("stem", stem_offsets_pos, stem_data_pos, num_stems, fst_pos - stem_data_pos, 4)
+    ];
+    for (name, start, end, items, data_len, unit) in tables.iter() {
+        let expected_len = items.checked_add(1).and_then(|count| count.checked_mul(8));
+        if expected_len != Some(end - start) {
+            return Err(format!("{} offsets take {} bytes, expected {} offsets", name, end - start, items + 1));
+        }
+        let mut previous = 0;
+        for i in 0..=*items as usize {
+            let offset = read_u64(bytes, *start as usize + i * 8);
+            if offset < previous {
+                return Err(format!("{} offset {} goes backwards", name, i));
+            }
+            previous = offset;
+        }
+        if previous.checked_mul(*unit).map(|last_len| last_len > *data_len).unwrap_or(true) {
+            return Err(format!("{} offsets point past their data", name));
+        }
+    }
+
+    // Offsets are known to be in bounds now, so only the contents they point at are left
+    let offset = |offsets_pos: u64, index: usize| read_u64(bytes, offsets_pos as usize + index * 8) as usize;
+    for id in 0..num_strings as usize {
+        let (start, end) = (offset(string_offsets_pos, id), offset(string_offsets_pos, id + 1));
+        let string_bytes = &bytes[string_data_pos as usize + start..string_data_pos as usize + end];
+        if std::str::from_utf8(string_bytes).is_err() {
+            return Err(format!("string {} is not utf8", id));
+        }
+    }
+    for entry in 0..num_entries as usize {
+        let (start, end) = (offset(entry_offsets_pos, entry), offset(entry_offsets_pos, entry + 1));
+        if start == end {
+            return Err(format!("entry {} has no key", entry));
+        }
+        if (start..end).any(|i| read_u32(bytes, entry_data_pos as usize + i * 4) as u64 >= num_strings) {
+            return Err(format!("entry {} lists a string id past {}", entry, num_strings));
+        }
+    }
+    let stem_data_end = offset(stem_offsets_pos, num_stems as usize);
+    if (0..stem_data_end).any(|i| read_u32(bytes, stem_data_pos as usize + i * 4) as u64 >= num_entries) {
+        return Err(format!("stem data lists an entry id past {}", num_entries));
+    }
+    Ok(())
+}
+
+/**
+ * Maps a binary association file, nothing is parsed or copied up front.
+ */
+pub fn open_binary_index(file_path: &str) -> io::Result<BinaryIndex> {
+    let mmap = Arc::new(unsafe { Mmap::map(&File::open(file_path)?)? });
+    if mmap.len() < HEADER_LEN || &mmap[0..8] != MAGIC {
+        return Err(invalid_data(format!("{} is not a binary association file", file_path)));
+    }
+    let version = read_u32(&mmap, 8);
+    if version != VERSION {
+        return Err(invalid_data(format!("{} has version {}, expected {}", file_path, version, VERSION)));
+    }
+    if let Err(message) = check_layout(&mmap) {
+        return Err(invalid_data(format!("{} is truncated or corrupt: {}", file_path, message)));
+    }
+    let max_group = read_u32(&mmap, 12) as usize;
+    let include_whole = read_u32(&mmap, 16) != 0;
+    let (source_size, source_mtime) = (read_u64(&mmap, 128), read_u64(&mmap, 136));
+    let fst_pos = read_u64(&mmap, 96) as usize;
+    let fst_len = read_u64(&mmap, 104) as usize;
+    let degree_fst_pos = read_u64(&mmap, 112) as usize;
+    let degree_fst_len = read_u64(&mmap, 120) as usize;
+    let section = MmapSection{mmap: mmap.clone(), start: fst_pos, end: fst_pos + fst_len};
+    let stems = Map::new(section).map_err(|e| invalid_data(format!("{}: {}", file_path, e)))?;
+    let section = MmapSection{mmap: mmap.clone(), start: degree_fst_pos, end: degree_fst_pos + degree_fst_len};
+    let degrees = Map::new(section).map_err(|e| invalid_data(format!("{}: {}", file_path, e)))?;
+    let num_strings = read_u64(&mmap, 24) as usize;
+    let article_ids = (0..num_strings).map(|_| AtomicU32::new(UNMAPPED)).collect();
+    Ok(BinaryIndex{
+        num_strings,
+        num_entries: read_u64(&mmap, 32) as usize,
+        num_stems: read_u64(&mmap, 40) as usize,
+        string_offsets_pos: read_u64(&mmap, 48) as usize,
+        string_data_pos: read_u64(&mmap, 56) as usize,
+        entry_offsets_pos: read_u64(&mmap, 64) as usize,
+        entry_data_pos: read_u64(&mmap, 72) as usize,
+        stem_offsets_pos: read_u64(&mmap, 80) as usize,
+        stem_data_pos: read_u64(&mmap, 88) as usize,
+        mmap,
+        stems,
+        degrees,
+        max_group,
+        include_whole,
+        source_size,
+        source_mtime,
+        article_ids
+    })
+}
+
+impl BinaryIndex {
+    pub fn num_strings(&self) -> usize {
+        self.num_strings
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.num_entries
+    }
+
+    // Stem grouping the index was built with
+    pub fn max_group(&self) -> usize {
+        self.max_group
+    }
+
+    pub fn include_whole(&self) -> bool {
+        self.include_whole
+    }
+
+    /**
+     * Compares the build parameters and the source file stamp recorded when the file was written
+     * with the ones asked for, returns a description of the first mismatch.
+     */
+    pub fn stamp_mismatch(&self, source_path: &str, max_group: usize, include_whole: bool) -> Option<String> {
+        if self.max_group != max_group || self.include_whole != include_whole {
+            return Some(format!("built with max_group {} and include_whole {}, configured {} and {}",
+                                self.max_group, self.include_whole, max_group, include_whole));
+        }
+        match manifest::source_stamp(source_path) {
+            Ok((size, mtime)) if size != self.source_size || mtime != self.source_mtime => {
+                Some(format!("{} changed since it was built", source_path))
+            },
+            Ok(_) => None,
+            Err(e) => Some(format!("can't read {}: {}", source_path, e))
+        }
+    }
+
+    pub fn string(&self, id: u32) -> Result<&str, String> {
+        if id as usize >= self.num_strings {
+            return Err(format!("string id {} out of range ({} strings)", id, self.num_strings));
+        }
+        let pos = self.string_offsets_pos + (id as usize) * 8;
+        let start = self.string_data_pos + read_u64(&self.mmap, pos) as usize;
+        let end = self.string_data_pos + read_u64(&self.mmap, pos + 8) as usize;
+        std::str::from_utf8(&self.mmap[start..end]).map_err(|e| format!("string {}: {}", id, e))
+    }
+
+    // Id of a local string, only shared ids are cached since query ids die with the query
+    pub fn article_id(&self, id: u32, titles: &QueryTitles) -> Result<ArticleId, String> {
+        let string = self.string(id)?;
+        let mapped = self.article_ids[id as usize].load(Ordering::Relaxed);
+        if mapped != UNMAPPED {
+            return Ok(mapped);
+        }
+        Ok(match titles.shared().get(string) {
+            Some(shared) => {
+                self.article_ids[id as usize].store(shared, Ordering::Relaxed);
+                shared
+            },
+            None => titles.id(string)
+        })
+    }
+
+    // u32 slice [start, end) of a section
+    fn u32_range(&self, offsets_pos: usize, index: usize) -> (usize, usize) {
+        let pos = offsets_pos + index * 8;
+        (read_u64(&self.mmap, pos) as usize, read_u64(&self.mmap, pos + 8) as usize)
+    }
+
+    // Key string id and article string ids of an entry (original line)
+    pub fn entry(&self, entry: u32) -> (u32, impl Iterator<Item = u32> + '_) {
+        let (start, end) = self.u32_range(self.entry_offsets_pos, entry as usize);
+        let key = read_u32(&self.mmap, self.entry_data_pos + start * 4);
+        let articles = (start + 1..end).map(move |i| read_u32(&self.mmap, self.entry_data_pos + i * 4));
+        (key, articles)
+    }
+
+    // Entries whose key generated the given stem
+    pub fn stem_entries(&self, stem: &str) -> Vec<u32> {
+        match self.stems.get(stem) {
+            Some(ordinal) if (ordinal as usize) < self.num_stems => {
+                let (start, end) = self.u32_range(self.stem_offsets_pos, ordinal as usize);
+                (start..end).map(|i| read_u32(&self.mmap, self.stem_data_pos + i * 4)).collect()
+            },
+            _ => Vec::new()
+        }
+    }
+}
+
+impl Searchable for BinaryIndex {
+    fn search(&self, term: &str, max_group: usize, include_whole: bool, titles: &QueryTitles) -> HashMap<ArticleId, ArticleId> {
+        let mut result_map: HashMap<ArticleId, ArticleId> = HashMap::new();
+        for stem in stemmer::generate_stems(term, max_group, include_whole) {
+            for entry in self.stem_entries(&stem) {
+                let (key, articles) = self.entry(entry);
+                // Ids were checked when the file was opened
+                let title = match self.article_id(key, titles) {
+                    Ok(title) => title,
+                    Err(_) => continue
+                };
+                for article in articles.filter_map(|article| self.article_id(article, titles).ok()) {
+                    result_map.insert(article, title);
+                }
+            }
+        }
+        result_map
+    }
+    fn in_degree(&self, article: ArticleId, titles: &QueryTitles) -> u64 {
+        self.degrees.get(titles.resolve(article).as_bytes()).unwrap_or(0)
+    }
+
+    fn num_lines(&self) -> u64 {
+        self.num_entries as u64
+    }
+}
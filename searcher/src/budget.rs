@@ -0,0 +1,96 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Why a query stopped before finishing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    // The client went away (or asked to stop)
+    Cancelled,
+    TimedOut,
+    // Working set size when it went over max_size
+    TooLarge(usize)
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Cancelled => write!(f, "query cancelled"),
+            Stop::TimedOut => write!(f, "query timed out"),
+            Stop::TooLarge(size) => write!(f, "working size {} over the maximum", size)
+        }
+    }
+}
+
+impl Stop {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Stop::Cancelled => "cancelled",
+            Stop::TimedOut => "timed_out",
+            Stop::TooLarge(_) => "size_exceeded"
+        }
+    }
+}
+
+/**
+ * Shared flag to stop a running query from another thread, e.g. when its connection closes
+ */
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/**
+ * How far a single query may go: a cap on association dict entries and a deadline.
+ * Stages call check as they fill their association dicts and give up with its error.
+ */
+pub struct Budget {
+    pub max_size: usize,
+    deadline: Option<Instant>,
+    token: CancelToken,
+    // First reason check failed, so the caller can tell a budget stop from a stage error
+    stopped: Mutex<Option<Stop>>
+}
+
+impl Budget {
+    pub fn new(max_size: usize, timeout: Option<Duration>) -> Budget {
+        Budget{max_size, deadline: timeout.map(|timeout| Instant::now() + timeout), token: CancelToken::default(), stopped: Mutex::new(None)}
+    }
+
+    pub fn unlimited() -> Budget {
+        Budget::new(usize::MAX, None)
+    }
+
+    pub fn token(&self) -> CancelToken {
+        self.token.clone()
+    }
+
+    // Err once cancelled, past the deadline or when size is over max_size
+    pub fn check(&self, size: usize) -> Result<(), String> {
+        let stop = if self.token.is_cancelled() {
+            Stop::Cancelled
+        } else if self.deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+            Stop::TimedOut
+        } else if size > self.max_size {
+            Stop::TooLarge(size)
+        } else {
+            return Ok(());
+        };
+        let mut stopped = self.stopped.lock().unwrap();
+        let stop = *stopped.get_or_insert(stop);
+        Err(stop.to_string())
+    }
+
+    pub fn stopped(&self) -> Option<Stop> {
+        *self.stopped.lock().unwrap()
+    }
+}
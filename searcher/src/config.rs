@@ -0,0 +1,194 @@
+extern crate serde_json;
+
+use std::fs;
+use std::io;
+
+use serde_json::{json, Value};
+
+/**
+ * How an index is loaded
+ * - fst: stems in an fst on disk, association lines read from the source file per query
+ * - in-memory: everything parsed into memory (fastest, uses the most memory)
+ * - binary: mmapped <path>.bin written by build-binary
+ * - stemmed: one fst key per (stem, line), association lines held in memory
+ * - synonym: comma separated word list, first word is the root
+ * - lexicon: every word/title in a word list or association file, for spelling based stages
+ * - pronunciation: CMUdict style pronunciation dictionary
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Fst,
+    InMemory,
+    Binary,
+    Stemmed,
+    Synonym,
+    Lexicon,
+    Pronunciation
+}
+
+impl Backend {
+    pub fn parse(name: &str) -> Option<Backend> {
+        match name {
+            "fst" => Some(Backend::Fst),
+            "in-memory" => Some(Backend::InMemory),
+            "binary" => Some(Backend::Binary),
+            "stemmed" => Some(Backend::Stemmed),
+            "synonym" => Some(Backend::Synonym),
+            "lexicon" => Some(Backend::Lexicon),
+            "pronunciation" => Some(Backend::Pronunciation),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Fst => "fst",
+            Backend::InMemory => "in-memory",
+            Backend::Binary => "binary",
+            Backend::Stemmed => "stemmed",
+            Backend::Synonym => "synonym",
+            Backend::Lexicon => "lexicon",
+            Backend::Pronunciation => "pronunciation"
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    pub name: String,
+    pub path: String,
+    pub backend: Backend,
+    // Stem grouping used when building the index
+    pub max_group: usize,
+    pub include_whole: bool,
+    // Query stages this index serves, in the order they're searched
+    pub stages: Vec<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub indexes: Vec<IndexConfig>,
+    // Address to listen on, the port comes from the command line
+    pub bind_address: String,
+    // Connections served at once, each query can use several GB
+    pub workers: usize,
+    // Connections waiting for a worker before new ones get 503
+    pub queue_size: usize
+}
+
+pub const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+pub const DEFAULT_WORKERS: usize = 2;
+pub const DEFAULT_QUEUE_SIZE: usize = 16;
+
+impl Default for ServerConfig {
+    // The indexes the server always used to load, the table index alone is ~30 GB
+    fn default() -> ServerConfig {
+        let index = |name: &str, path: &str, backend, max_group, include_whole, stages: &[&str]| IndexConfig{
+            name: name.to_string(),
+            path: path.to_string(),
+            backend,
+            max_group,
+            include_whole,
+            stages: stages.iter().map(|stage| stage.to_string()).collect()
+        };
+        ServerConfig{indexes: vec![
+            index("norm", "big_norm_index.txt", Backend::InMemory, 1, true, &["WikiAllStem", "WikiArticleStem", "WikiArticleExact"]),
+            index("table", "big_table_index.txt", Backend::Fst, 1, false, &["WikiAllStem", "Fuzzy", "Prefix", "Pattern"]),
+            index("synonyms", "moby_words.txt", Backend::Synonym, 1, false, &["Synonym"]),
+            index("homophones", "homophone_list.txt", Backend::Synonym, 1, false, &["Homophone"]),
+            index("words", "moby_words.txt", Backend::Lexicon, 1, false, &["Anagram", "Phonetic", "Substring", "Transform"])
+        ], bind_address: DEFAULT_BIND_ADDRESS.to_string(), workers: DEFAULT_WORKERS, queue_size: DEFAULT_QUEUE_SIZE}
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl IndexConfig {
+    fn from_json(v: &Value, position: usize) -> Result<IndexConfig, String> {
+        let object = v.as_object().ok_or(format!("index #{} is not an object", position))?;
+        let name = match object.get("name") {
+            Some(name) => name.as_str().ok_or(format!("index #{}: name must be a string", position))?.to_string(),
+            None => format!("index{}", position)
+        };
+        let path = object.get("path").and_then(|path| path.as_str())
+            .ok_or(format!("{}: path is required", name))?
+            .to_string();
+        let backend_name = object.get("backend").and_then(|backend| backend.as_str())
+            .ok_or(format!("{}: backend is required", name))?;
+        let backend = Backend::parse(backend_name)
+            .ok_or(format!("{}: unknown backend {:?} (fst, in-memory, binary, stemmed, synonym, lexicon or pronunciation)", name, backend_name))?;
+        let max_group = match object.get("max_group") {
+            Some(max_group) => max_group.as_u64().ok_or(format!("{}: max_group must be a number", name))? as usize,
+            None => 1
+        };
+        let include_whole = match object.get("include_whole") {
+            Some(include_whole) => include_whole.as_bool().ok_or(format!("{}: include_whole must be true or false", name))?,
+            None => false
+        };
+        let mut stages: Vec<String> = Vec::new();
+        for stage in object.get("stages").and_then(|stages| stages.as_array()).ok_or(format!("{}: stages is required", name))? {
+            stages.push(stage.as_str().ok_or(format!("{}: stages must be strings", name))?.to_string());
+        }
+        Ok(IndexConfig{name, path, backend, max_group, include_whole, stages})
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "path": self.path,
+            "backend": self.backend.name(),
+            "max_group": self.max_group,
+            "include_whole": self.include_whole,
+            "stages": self.stages
+        })
+    }
+}
+
+impl ServerConfig {
+    /**
+     * Config format, max_group defaults to 1, include_whole to false and the server settings to these:
+     * - {"indexes": [{"name": "norm", "path": "big_norm_index.txt", "backend": "in-memory", "max_group": 1, "include_whole": true, "stages": ["WikiArticleStem"]}, ...],
+     *   "bind_address": "127.0.0.1", "workers": 2, "queue_size": 16}
+     */
+    pub fn from_json(v: &Value) -> Result<ServerConfig, String> {
+        let indexes_array = v.get("indexes").and_then(|indexes| indexes.as_array())
+            .ok_or("indexes must be a list")?;
+        let mut indexes: Vec<IndexConfig> = Vec::new();
+        for (position, index_value) in indexes_array.iter().enumerate() {
+            let index = IndexConfig::from_json(index_value, position)?;
+            if indexes.iter().any(|other| other.name == index.name) {
+                return Err(format!("index name {} is used twice", index.name));
+            }
+            indexes.push(index);
+        }
+        let bind_address = match v.get("bind_address") {
+            Some(bind_address) => bind_address.as_str().ok_or("bind_address must be a string")?.to_string(),
+            None => DEFAULT_BIND_ADDRESS.to_string()
+        };
+        let count = |name: &str, default: usize| match v.get(name) {
+            Some(count) => count.as_u64().map(|count| count as usize).ok_or(format!("{} must be a number", name)),
+            None => Ok(default)
+        };
+        let workers = count("workers", DEFAULT_WORKERS)?;
+        if workers == 0 {
+            return Err("workers must be at least 1".to_string());
+        }
+        let queue_size = count("queue_size", DEFAULT_QUEUE_SIZE)?;
+        Ok(ServerConfig{indexes, bind_address, workers, queue_size})
+    }
+
+    pub fn to_json(&self) -> Value {
+        let indexes: Vec<Value> = self.indexes.iter().map(|index| index.to_json()).collect();
+        json!({"indexes": indexes, "bind_address": self.bind_address, "workers": self.workers, "queue_size": self.queue_size})
+    }
+}
+
+pub fn load_config(file_path: &str) -> io::Result<ServerConfig> {
+    let contents = fs::read_to_string(file_path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file_path, e)))?;
+    let v: Value = serde_json::from_str(&contents)
+        .map_err(|e| invalid_data(format!("{}: {}", file_path, e)))?;
+    ServerConfig::from_json(&v).map_err(|message| invalid_data(format!("{}: {}", file_path, message)))
+}
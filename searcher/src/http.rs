@@ -0,0 +1,375 @@
+extern crate httparse;
+
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// Request line and headers together
+pub const MAX_HEAD_BYTES: u64 = 64 * 1024;
+pub const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+const MAX_HEADERS: usize = 64;
+
+#[derive(Debug)]
+pub enum HttpError {
+    // Reading or writing the connection failed (including idle timeouts)
+    Io(io::Error),
+    // Not something we can parse, the connection can't be reused after this
+    BadRequest(String),
+    // Headers or body beyond MAX_HEAD_BYTES/MAX_BODY_BYTES
+    TooLarge(String)
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Io(error) => write!(f, "{}", error),
+            HttpError::BadRequest(message) => write!(f, "bad request: {}", message),
+            HttpError::TooLarge(message) => write!(f, "request too large: {}", message)
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(error: io::Error) -> HttpError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => HttpError::BadRequest("connection closed before the end of the request".to_string()),
+            _ => HttpError::Io(error)
+        }
+    }
+}
+
+impl HttpError {
+    // Status to answer with, None when the connection is gone
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::Io(_) => None,
+            HttpError::BadRequest(_) => Some(400),
+            HttpError::TooLarge(_) => Some(413)
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            HttpError::Io(_) => "io",
+            HttpError::BadRequest(_) => "bad_request",
+            HttpError::TooLarge(_) => "request_too_large"
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    // 0 for HTTP/1.0, 1 for HTTP/1.1
+    pub version: u8,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl Request {
+    // First value of a header, names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    fn header_has_token(&self, name: &str, token: &str) -> bool {
+        self.headers.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|part| part.trim().eq_ignore_ascii_case(token))
+    }
+
+    /**
+     * HTTP/1.1 connections stay open unless the client sends Connection: close,
+     * HTTP/1.0 ones only with Connection: keep-alive
+     */
+    pub fn keep_alive(&self) -> bool {
+        if self.header_has_token("Connection", "close") {
+            return false;
+        }
+        self.version >= 1 || self.header_has_token("Connection", "keep-alive")
+    }
+}
+
+// One line including its \n, at most limit bytes
+fn read_line<R: BufRead>(reader: &mut R, line: &mut Vec<u8>, limit: u64) -> Result<usize, HttpError> {
+    let read = reader.by_ref().take(limit).read_until(b'\n', line)?;
+    if read as u64 == limit && !line.ends_with(b"\n") {
+        return Err(HttpError::TooLarge(format!("line longer than {} bytes", limit)));
+    }
+    Ok(read)
+}
+
+// Whether a read only gave up because of the socket's read timeout
+fn timed_out(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/**
+ * Reads through to reader until deadline, however slowly the bytes come in. Socket read timeouts
+ * before then are waited out, so the deadline is only overrun by one of them.
+ */
+struct DeadlineReader<'a, R> {
+    reader: &'a mut R,
+    deadline: Instant
+}
+
+impl<'a, R: BufRead> BufRead for DeadlineReader<'a, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        loop {
+            if Instant::now() >= self.deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long to arrive"));
+            }
+            match self.reader.fill_buf() {
+                Ok(_) => break,
+                Err(error) if timed_out(&error) => continue,
+                Err(error) => return Err(error)
+            }
+        }
+        // Already buffered, this doesn't read again
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+    }
+}
+
+impl<'a, R: BufRead> Read for DeadlineReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let amount = available.len().min(buf.len());
+        buf[..amount].copy_from_slice(&available[..amount]);
+        self.consume(amount);
+        Ok(amount)
+    }
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, HttpError> {
+    let mut body: Vec<u8> = Vec::new();
+    loop {
+        let mut line: Vec<u8> = Vec::new();
+        if read_line(reader, &mut line, 1024)? == 0 {
+            return Err(HttpError::BadRequest("connection closed before the last chunk".to_string()));
+        }
+        let line = String::from_utf8_lossy(&line);
+        // Chunk extensions after ; are ignored
+        let size_text = line.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size_text, 16) {
+            Ok(size) => size,
+            Err(_) => return Err(HttpError::BadRequest(format!("bad chunk size {:?}", size_text)))
+        };
+        if size == 0 {
+            break;
+        }
+        if body.len() + size > MAX_BODY_BYTES {
+            return Err(HttpError::TooLarge(format!("body over {} bytes", MAX_BODY_BYTES)));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        let mut end: Vec<u8> = Vec::new();
+        read_line(reader, &mut end, 2)?;
+        if end != b"\r\n" && end != b"\n" {
+            return Err(HttpError::BadRequest("chunk longer than its size".to_string()));
+        }
+    }
+    // Trailers, up to the blank line
+    loop {
+        let mut line: Vec<u8> = Vec::new();
+        if read_line(reader, &mut line, MAX_HEAD_BYTES)? == 0 || line == b"\r\n" || line == b"\n" {
+            return Ok(body);
+        }
+    }
+}
+
+fn content_length(request: &Request) -> Result<usize, HttpError> {
+    let mut length: Option<usize> = None;
+    for (_, value) in request.headers.iter().filter(|(key, _)| key.eq_ignore_ascii_case("Content-Length")) {
+        let parsed = match value.trim().parse::<usize>() {
+            Ok(parsed) => parsed,
+            Err(_) => return Err(HttpError::BadRequest(format!("bad Content-Length {:?}", value)))
+        };
+        if length.is_some() && length != Some(parsed) {
+            return Err(HttpError::BadRequest("conflicting Content-Length headers".to_string()));
+        }
+        length = Some(parsed);
+    }
+    Ok(length.unwrap_or(0))
+}
+
+/**
+ * Reads one request, its body sized by Content-Length or chunked Transfer-Encoding. Returns None
+ * when the client closed the connection between requests. Clients waiting on Expect: 100-continue
+ * get their go ahead through writer. The whole request has to arrive within timeout.
+ */
+pub fn read_request<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, timeout: Duration) -> Result<Option<Request>, HttpError> {
+    let reader = &mut DeadlineReader{reader, deadline: Instant::now() + timeout};
+    let mut head: Vec<u8> = Vec::new();
+    loop {
+        let remaining = MAX_HEAD_BYTES.saturating_sub(head.len() as u64);
+        if remaining == 0 {
+            return Err(HttpError::TooLarge(format!("headers over {} bytes", MAX_HEAD_BYTES)));
+        }
+        if read_line(reader, &mut head, remaining)? == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(HttpError::BadRequest("connection closed before the end of the headers".to_string()));
+        }
+        // Stray blank lines between requests are allowed
+        if head == b"\r\n" || head == b"\n" {
+            head.clear();
+        } else if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+            break;
+        }
+    }
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    match parsed.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => {},
+        Ok(httparse::Status::Partial) => return Err(HttpError::BadRequest("incomplete headers".to_string())),
+        Err(error) => return Err(HttpError::BadRequest(error.to_string()))
+    }
+    let mut request = Request{
+        method: parsed.method.unwrap_or("").to_string(),
+        path: parsed.path.unwrap_or("").to_string(),
+        version: parsed.version.unwrap_or(1),
+        headers: parsed.headers.iter().map(|header| (header.name.to_string(), String::from_utf8_lossy(header.value).to_string())).collect(),
+        body: Vec::new()
+    };
+    let chunked = request.header_has_token("Transfer-Encoding", "chunked");
+    let length = if chunked { 0 } else { content_length(&request)? };
+    if length > MAX_BODY_BYTES {
+        return Err(HttpError::TooLarge(format!("body of {} bytes is over {} bytes", length, MAX_BODY_BYTES)));
+    }
+    if (chunked || length > 0) && request.header_has_token("Expect", "100-continue") {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    if chunked {
+        request.body = read_chunked(reader)?;
+    } else {
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body)?;
+    }
+    Ok(Some(request))
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown"
+    }
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Response {
+        Response{status, content_type, body: body.into()}
+    }
+
+    pub fn json(status: u16, body: String) -> Response {
+        Response::new(status, "application/json", body)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> io::Result<()> {
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+            self.status, reason(self.status), self.content_type, self.body.len(), if keep_alive { "keep-alive" } else { "close" });
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+// Headers for a Server-Sent Events response, the stream ends when the connection closes
+pub fn write_event_stream_head<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")?;
+    writer.flush()
+}
+
+// One event, each line of data gets its own data: field
+pub fn write_event<W: Write>(writer: &mut W, event: &str, data: &str) -> io::Result<()> {
+    let mut frame = format!("event: {}\n", event);
+    for line in data.split('\n') {
+        frame.push_str("data: ");
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    frame.push('\n');
+    writer.write_all(frame.as_bytes())?;
+    writer.flush()
+}
+
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/**
+ * Polls a connection in the background while its request is worked on, see watch_disconnect.
+ * Stops when dropped.
+ */
+pub struct DisconnectWatcher {
+    done: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl Drop for DisconnectWatcher {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/**
+ * Calls on_disconnect if the client closes the connection before the watcher is dropped.
+ * A client that pipelined its next request is still there. The read timeout is put back
+ * once the watcher stops.
+ */
+pub fn watch_disconnect<F: FnOnce() + Send + 'static>(stream: &TcpStream, on_disconnect: F) -> io::Result<DisconnectWatcher> {
+    let stream = stream.try_clone()?;
+    let previous_timeout = stream.read_timeout()?;
+    stream.set_read_timeout(Some(WATCH_INTERVAL))?;
+    let done = Arc::new(AtomicBool::new(false));
+    let thread_done = done.clone();
+    let handle = thread::spawn(move || {
+        let mut byte = [0; 1];
+        while !thread_done.load(Ordering::Relaxed) {
+            match stream.peek(&mut byte) {
+                Ok(0) => {
+                    on_disconnect();
+                    break;
+                },
+                Ok(_) => thread::sleep(WATCH_INTERVAL),
+                Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {},
+                Err(_) => {
+                    on_disconnect();
+                    break;
+                }
+            }
+        }
+        let _ = stream.set_read_timeout(previous_timeout);
+    });
+    Ok(DisconnectWatcher{done, handle: Some(handle)})
+}
@@ -0,0 +1,245 @@
+extern crate serde_json;
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use regex::Regex;
+use serde_json::json;
+
+// Number of (item, article) pairs held in memory per output before spilling a sorted run to disk
+pub const DEFAULT_MAX_BUFFERED_PAIRS: usize = 5_000_000;
+
+// condensed.csv stores each record as three JSON encoded lines
+const RECORD_FIELDS: usize = 3;
+
+/**
+ * Everything we pull out of a single article page
+ * - list_items: text of ordered/unordered list items (only used by the full index, very noisy)
+ * - table_items: individual cells of table rows
+ */
+#[derive(Debug, Default)]
+pub struct PageExtraction {
+    pub list_items: Vec<String>,
+    pub table_items: Vec<String>
+}
+
+#[derive(Debug, Default)]
+pub struct BuildStats {
+    pub records: u64,
+    pub skipped: u64,
+    pub norm_entries: u64,
+    pub table_entries: u64
+}
+
+/**
+ * Same extraction as condensed_article_searcher.py. Like the script, [[link]] targets are only
+ * used to skip list items that start with one, they never make it into the index files.
+ */
+pub fn extract_page(page: &str) -> PageExtraction {
+    lazy_static! {
+        static ref MARKUP_LINK_RE: Regex = Regex::new(r"\[\[([^|\]]{1,256})(\|[^\]]{1,256})?\]\]").unwrap();
+        // can be 1., - + * for leading items, also can have leading spaces
+        static ref LIST_ITEM_RE: Regex = Regex::new(r"(?m)^\s*([*-+]+|[0-9]+\.)\s*([^\n]{4,256})$").unwrap();
+        // Only look for |- rows, either a single line (|| delimited) or a line for each item (| start)
+        static ref TABLE_ROW_RE: Regex = Regex::new(r"(?m)^\|-\n((\|\s+[^\n]+)+)$").unwrap();
+        static ref STYLE_RE: Regex = Regex::new(r"(?i)^\|\s+style=[^|]+\|(.+)$").unwrap();
+    }
+    let mut extraction = PageExtraction::default();
+    for cap in LIST_ITEM_RE.captures_iter(page) {
+        let item = &cap[2];
+        // Items that start with a link are already covered by the link
+        if let Some(link_match) = MARKUP_LINK_RE.find(item) {
+            if link_match.start() == 0 {
+                continue;
+            }
+        }
+        extraction.list_items.push(item.to_string());
+    }
+    for cap in TABLE_ROW_RE.captures_iter(page) {
+        let mut raw_row = cap.get(1).unwrap().as_str();
+        // could start with '| style="'
+        if let Some(style_cap) = STYLE_RE.captures(raw_row) {
+            raw_row = style_cap.get(1).unwrap().as_str();
+        }
+        for component in raw_row.trim_start_matches('|').split("||") {
+            let norm = component.trim().to_lowercase();
+            if !norm.is_empty() {
+                extraction.table_items.push(norm);
+            }
+        }
+    }
+    extraction
+}
+
+/**
+ * Collects (item, article) pairs for one output file, spilling sorted runs to disk
+ * once max_buffered pairs are held so memory stays bounded.
+ */
+struct RunSpiller {
+    pairs: Vec<(String, String)>,
+    runs: Vec<PathBuf>,
+    run_dir: PathBuf,
+    max_buffered: usize
+}
+
+impl RunSpiller {
+    fn new(run_dir: PathBuf, max_buffered: usize) -> io::Result<RunSpiller> {
+        fs::create_dir_all(&run_dir)?;
+        Ok(RunSpiller{pairs: Vec::new(), runs: Vec::new(), run_dir, max_buffered})
+    }
+
+    fn push(&mut self, item: &str, article: &str) -> io::Result<()> {
+        self.pairs.push((item.to_string(), article.to_string()));
+        if self.pairs.len() >= self.max_buffered {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.pairs.is_empty() {
+            return Ok(());
+        }
+        self.pairs.sort_unstable();
+        self.pairs.dedup();
+        let run_path = self.run_dir.join(format!("run_{}.txt", self.runs.len()));
+        let mut writer = BufWriter::new(File::create(&run_path)?);
+        for pair in self.pairs.drain(..) {
+            writeln!(writer, "{}", json!([pair.0, pair.1]))?;
+        }
+        writer.flush()?;
+        self.runs.push(run_path);
+        Ok(())
+    }
+
+    /**
+     * K-way merges all runs into the final association file, one line per item:
+     * - ["item", ["sorted", "deduplicated", "articles"]]
+     */
+    fn finish(mut self, output_path: &Path) -> io::Result<u64> {
+        self.spill()?;
+        let mut readers: Vec<io::Lines<io::BufReader<File>>> = Vec::new();
+        for run in &self.runs {
+            readers.push(io::BufReader::new(File::open(run)?).lines());
+        }
+        let mut heap: BinaryHeap<Reverse<(String, String, usize)>> = BinaryHeap::new();
+        for (run_index, reader) in readers.iter_mut().enumerate() {
+            if let Some((item, article)) = next_pair(reader)? {
+                heap.push(Reverse((item, article, run_index)));
+            }
+        }
+
+        let mut writer = BufWriter::new(File::create(output_path)?);
+        let mut entries: u64 = 0;
+        let mut current_item: Option<String> = None;
+        let mut current_articles: Vec<String> = Vec::new();
+        while let Some(Reverse((item, article, run_index))) = heap.pop() {
+            if current_item.as_ref() != Some(&item) {
+                if let Some(finished_item) = current_item.take() {
+                    writeln!(writer, "{}", json!([finished_item, current_articles]))?;
+                    entries += 1;
+                }
+                current_item = Some(item);
+                current_articles = Vec::new();
+            }
+            // Runs are sorted by (item, article) so duplicates are always adjacent
+            if current_articles.last() != Some(&article) {
+                current_articles.push(article);
+            }
+            if let Some((next_item, next_article)) = next_pair(&mut readers[run_index])? {
+                heap.push(Reverse((next_item, next_article, run_index)));
+            }
+        }
+        if let Some(finished_item) = current_item {
+            writeln!(writer, "{}", json!([finished_item, current_articles]))?;
+            entries += 1;
+        }
+        writer.flush()?;
+        fs::remove_dir_all(&self.run_dir)?;
+        Ok(entries)
+    }
+}
+
+fn next_pair(reader: &mut io::Lines<io::BufReader<File>>) -> io::Result<Option<(String, String)>> {
+    match reader.next() {
+        Some(line) => {
+            let pair: (String, String) = serde_json::from_str(&line?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Some(pair))
+        },
+        None => Ok(None)
+    }
+}
+
+/**
+ * Streams a WikiExtractor condensed.csv (title, categories, page as JSON strings, one per line)
+ * and writes both association files in a single pass:
+ * - norm: table items, which are more reliable and structured
+ * - table: everything in norm plus list items
+ */
+pub fn build_association_indexes(condensed_path: &str,
+                                 norm_path: &str,
+                                 table_path: &str,
+                                 max_buffered_pairs: usize) -> io::Result<BuildStats> {
+    let process_start = Instant::now();
+    let mut norm_spiller = RunSpiller::new(PathBuf::from(format!("{}.runs", norm_path)), max_buffered_pairs)?;
+    let mut table_spiller = RunSpiller::new(PathBuf::from(format!("{}.runs", table_path)), max_buffered_pairs)?;
+    let mut stats = BuildStats::default();
+
+    let reader = io::BufReader::new(File::open(condensed_path)?);
+    let mut record: Vec<String> = Vec::with_capacity(RECORD_FIELDS);
+    for line in reader.lines() {
+        record.push(line?);
+        if record.len() < RECORD_FIELDS {
+            continue;
+        }
+        let page_raw = &record[2];
+        stats.records += 1;
+        if stats.records % 1000000 == 0 {
+            println!("records: {}", stats.records);
+        }
+        if page_raw.starts_with("\"{{Not English") {
+            stats.skipped += 1;
+            record.clear();
+            continue;
+        }
+        let title: String = match serde_json::from_str::<String>(&record[0]) {
+            Ok(title) => title.trim().to_lowercase(),
+            Err(_) => {
+                stats.skipped += 1;
+                record.clear();
+                continue;
+            }
+        };
+        let page: String = match serde_json::from_str(page_raw) {
+            Ok(page) => page,
+            Err(_) => {
+                stats.skipped += 1;
+                record.clear();
+                continue;
+            }
+        };
+        let extraction = extract_page(&page);
+        for item in &extraction.table_items {
+            norm_spiller.push(item, &title)?;
+            table_spiller.push(item, &title)?;
+        }
+        for item in &extraction.list_items {
+            table_spiller.push(item, &title)?;
+        }
+        record.clear();
+    }
+    println!("Finished extracting {} records in: {} seconds", stats.records, process_start.elapsed().as_secs());
+
+    let merge_start = Instant::now();
+    stats.norm_entries = norm_spiller.finish(Path::new(norm_path))?;
+    stats.table_entries = table_spiller.finish(Path::new(table_path))?;
+    println!("Finished merging in: {} seconds", merge_start.elapsed().as_secs());
+    Ok(stats)
+}
@@ -4,19 +4,29 @@ extern crate serde_json;
 extern crate simd_json; 
 
 use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{BufRead,Write};
+use std::io::BufRead;
 use std::path::Path;
 use std::time::Instant;
 use memmap::Mmap;
 
-use fst::{Map, MapBuilder};
+use fst::{IntoStreamer, Map, MapBuilder, Streamer};
+use fst::Automaton;
+use fst::automaton::{Levenshtein, Str};
 
 use serde_json::Value;
-use serde_json::json;
+use serde_json::error::Category;
 
+use super::manifest;
+use super::pattern::KeyPattern;
+use super::manifest::{ContentHasher, IndexManifest};
+use super::postings;
+use super::postings::{Postings, PostingsWriter};
 use super::stemmer;
+use super::titles::{ArticleId, QueryTitles, TitleDict};
 
 // The output is wrapped in a Result to allow matching on errors
 // Returns an Iterator to the Reader of the lines of the file.
@@ -26,36 +36,160 @@ where P: AsRef<Path>, {
     Ok(io::BufReader::new(file).lines())
 }
 
+/**
+ * Why an index couldn't be loaded, line numbers start at 1
+ */
+#[derive(Debug)]
+pub enum IndexError {
+    // Source file doesn't exist
+    Missing{file: String},
+    // Line isn't valid JSON (or UTF-8)
+    Malformed{file: String, line: usize, message: String},
+    // Valid JSON but not ["text", ["article", ...]]
+    Shape{file: String, line: usize, message: String},
+    Io{file: String, error: io::Error}
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexError::Missing{file} => write!(f, "{}: file not found", file),
+            IndexError::Malformed{file, line, message} => write!(f, "{}:{}: malformed line: {}", file, line, message),
+            IndexError::Shape{file, line, message} => write!(f, "{}:{}: unexpected shape: {}", file, line, message),
+            IndexError::Io{file, error} => write!(f, "{}: {}", file, error)
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+impl IndexError {
+    pub fn io(file: &str, error: io::Error) -> IndexError {
+        if error.kind() == io::ErrorKind::NotFound {
+            IndexError::Missing{file: file.to_string()}
+        } else {
+            IndexError::Io{file: file.to_string(), error}
+        }
+    }
+
+    pub fn fst(file: &str, error: fst::Error) -> IndexError {
+        match error {
+            fst::Error::Io(error) => IndexError::io(file, error),
+            other => IndexError::Io{file: file.to_string(), error: io::Error::new(io::ErrorKind::InvalidData, other.to_string())}
+        }
+    }
+
+    // Errors from reading a single line, bad UTF-8 counts as a malformed line
+    pub fn line(file: &str, line: usize, error: io::Error) -> IndexError {
+        if error.kind() == io::ErrorKind::InvalidData {
+            IndexError::Malformed{file: file.to_string(), line, message: error.to_string()}
+        } else {
+            IndexError::io(file, error)
+        }
+    }
+}
+
+pub fn open_lines(file_path: &str) -> Result<io::Lines<io::BufReader<File>>, IndexError> {
+    read_lines(file_path).map_err(|e| IndexError::io(file_path, e))
+}
+
+/**
+ * Parses one association line:
+ * - ["text", ["a", "bunch", "of", "article", "titles", "containing", "text"]]
+ */
+pub fn parse_association_line(file_path: &str, line: usize, entry: &str) -> Result<(String, Vec<String>), IndexError> {
+    serde_json::from_str(entry).map_err(|e| {
+        let message = e.to_string();
+        match e.classify() {
+            Category::Data => IndexError::Shape{file: file_path.to_string(), line, message},
+            _ => IndexError::Malformed{file: file_path.to_string(), line, message}
+        }
+    })
+}
+
 pub struct FstIndex {
-    // vector of fst_value -> orig_lines
-    fst_values: Vec<Vec<u64>>,
+    // fst_value -> orig_lines, decoded per lookup
+    postings: Postings,
     // Byte offsets of each line in original index file
     line_starts: Vec<u64>,
-    // Original association file path
+    // Original association file path, and its contents mapped once at load
     association_file: String,
-    // FST file path
+    association_map: Mmap,
+    // FST file path, and the stem -> fst_value map it holds
     fst_file: String,
-    // max grouping threshold
-    max_group: usize
+    stems: Map<Mmap>,
+    // article title -> number of lines listing it
+    degrees: Map<Mmap>
 }
 
 pub struct InMemoryIndex {
     index: HashMap<String, Vec<usize>>,
-    lines: Vec<Vec<String>>
+    // key followed by its articles
+    lines: Vec<Vec<ArticleId>>,
+    // article -> number of lines listing it
+    degrees: HashMap<ArticleId, u32>
 }
 
 pub struct StemmedIndex {
-    // FST file
-    fst_file: String,
-    // K threshold
-    max_group: usize,
-    // Original Vector
-    orig_vec: Vec<Vec<String>>
+    // stem \0 line -> line, one key per (stem, line) pair since fst keys must be unique
+    map: Map<Mmap>,
+    // key followed by its articles, by line
+    orig_vec: Vec<Vec<ArticleId>>,
+    // article -> number of lines listing it
+    degrees: HashMap<ArticleId, u32>
 }
 
 pub trait Searchable {
-    // Search
-    fn search(&self, term: &str, max_group: usize, include_whole: bool) -> HashMap<String, String>;
+    // Search, returns article -> key that matched the term
+    fn search(&self, term: &str, max_group: usize, include_whole: bool, titles: &QueryTitles) -> HashMap<ArticleId, ArticleId>;
+
+    // Search for stems within distance edits, returns article -> (key that matched, edits)
+    fn search_fuzzy(&self, _term: &str, _max_group: usize, _include_whole: bool, _distance: u32, _titles: &QueryTitles) -> Result<HashMap<ArticleId, (ArticleId, u32)>, String> {
+        Err("fuzzy search needs an fst index".to_string())
+    }
+
+    // Search every key matching pattern, fails if more than max_keys keys match
+    fn search_keys(&self, _pattern: &KeyPattern, _max_keys: usize, _titles: &QueryTitles) -> Result<HashMap<ArticleId, ArticleId>, String> {
+        Err("prefix and pattern search need an fst index".to_string())
+    }
+
+    // Number of lines (keys) listing article, hub articles are listed by a lot of them
+    fn in_degree(&self, _article: ArticleId, _titles: &QueryTitles) -> u64 {
+        0
+    }
+
+    // Number of lines (keys) in the index, 0 if in_degree isn't known
+    fn num_lines(&self) -> u64 {
+        0
+    }
+}
+
+// article -> number of lines listing it, for indexes holding every line in memory
+fn count_degrees(lines: &[Vec<ArticleId>]) -> HashMap<ArticleId, u32> {
+    let mut degrees: HashMap<ArticleId, u32> = HashMap::new();
+    for line in lines {
+        for article in &line[1..] {
+            *degrees.entry(*article).or_default() += 1;
+        }
+    }
+    degrees
+}
+
+/**
+ * Levenshtein distance between two stems, counted in chars
+ */
+pub fn edit_distance(a: &str, b: &str) -> u32 {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<u32> = (0..=b.len() as u32).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i as u32 + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
 }
 
 #[derive(Eq, Ord, PartialEq, PartialOrd)]
@@ -64,299 +198,568 @@ pub struct StemChunk {
     index: u64
 }
 
+impl StemChunk {
+    // Sorts the same way as (stem, index) so chunks can be inserted in order
+    fn key(&self) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.stem.len() + 9);
+        key.extend_from_slice(self.stem.as_bytes());
+        key.push(0);
+        key.extend_from_slice(&self.index.to_be_bytes());
+        key
+    }
+}
+
 /**
  * Populates an index from a file with format:
  * - ["text", ["a", "bunch", "of", "article", "titles", "containing", "text"]]
  *
+ * Stems go into fst_<file>.fst and the lines for each stem into postings_<file>.bin.
+ * manifest_<file>.json is written last and describes the source they were built from,
+ * the side files are only re-used when it matches the source and parameters asked for.
  */
-pub fn generate_fst_index(file_path: &str, max_group: usize, include_whole: bool) -> Option<FstIndex> {
-    //TODO: if the file already exists, only do the part where we derive byte offsets
-    // stem map stores all stems and the indexes of articles they map to
-    let mut stem_map: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+pub fn generate_fst_index(file_path: &str, max_group: usize, include_whole: bool) -> Result<FstIndex, IndexError> {
+    let fst_file = side_file(file_path, "fst", "fst");
+    let postings_file = side_file(file_path, "postings", "bin");
+    let degree_file = side_file(file_path, "degree", "fst");
+    let legacy_values_file = side_file(file_path, "accessory", "map");
+    let manifest_file = side_file(file_path, "manifest", "json");
+
+    let (source_size, source_mtime) = manifest::source_stamp(file_path)
+        .map_err(|e| IndexError::io(file_path, e))?;
+    // content_hash is filled in once the source has been read
+    let mut expected = IndexManifest{
+        source_size,
+        source_mtime,
+        content_hash: 0,
+        builder_version: manifest::BUILDER_VERSION,
+        max_group,
+        include_whole
+    };
 
-    let fst_file = format!("{}_{}.{}", "fst", file_path, "fst");
-    let fst_values_file = format!("{}_{}.{}", "accessory", file_path, "map");
     let fst_exists = Path::new(&fst_file).exists();
-    let fst_values_exists = Path::new(&fst_values_file).exists();
-    let index_exists = fst_exists && fst_values_exists;
+    let mut postings_exists = Path::new(&postings_file).exists();
+    let mut existing_manifest = manifest::read_manifest(&manifest_file).unwrap_or(None);
+    // Built before postings files and manifests existed, their JSON values only need converting once
+    let mut legacy = fst_exists && !postings_exists && existing_manifest.is_none() && Path::new(&legacy_values_file).exists();
+    if legacy {
+        eprintln!("Converting {} to {}", legacy_values_file, postings_file);
+        match convert_legacy_values(&legacy_values_file, &postings_file) {
+            Ok(()) => postings_exists = true,
+            Err(e) => {
+                eprintln!("Failed to convert {} ({}), rebuilding.", legacy_values_file, e);
+                legacy = false;
+            }
+        }
+    }
 
-    let wtr: Option<io::BufWriter<File>>;
-    let mut build: Option<MapBuilder<io::BufWriter<File>>> = None;
-    let mut fst_values_writer: Option<io::LineWriter<File>> = None;
+    let mut index_exists = false;
+    if fst_exists && postings_exists {
+        if let Some(reason) = existing_manifest.as_ref().and_then(|m| m.stamp_mismatch(&expected)) {
+            eprintln!("Index files for {} are stale ({}), rebuilding.", file_path, reason);
+        } else if existing_manifest.is_none() && !legacy {
+            eprintln!("Index files for {} have no manifest (interrupted build?), rebuilding.", file_path);
+        } else if let Err(reason) = check_side_files(&fst_file, &postings_file) {
+            eprintln!("Index files for {} are damaged ({}), rebuilding.", file_path, reason);
+        } else {
+            if legacy {
+                eprintln!("Re-using converted legacy index files for {}, assuming they were built with max_group {} and include_whole {}.",
+                          file_path, max_group, include_whole);
+            } else {
+                eprintln!("Index files exist, re-using.");
+            }
+            index_exists = true;
+        }
+    }
     if !index_exists {
-        wtr = Some(io::BufWriter::new(File::create(&fst_file).unwrap()));
-        build = Some(MapBuilder::new(wtr.unwrap()).unwrap());
-        fst_values_writer = Some(io::LineWriter::new(File::create(&fst_values_file).unwrap()));
-    } else {
-        eprintln!("Index files exist, re-using.");
+        existing_manifest = None;
+    }
+    // Legacy files never had one, it's rebuilt on its own from the source pass below
+    let mut degrees_missing = false;
+    if index_exists {
+        if let Err(reason) = check_degree_file(&degree_file) {
+            eprintln!("Degree file for {} is missing or damaged ({}), rebuilding it.", file_path, reason);
+            degrees_missing = true;
+        }
     }
 
-    let fst_values: Vec<Vec<u64>> = Vec::new();
-    let line_starts: Vec<u64> = Vec::new();
     let association_file = file_path.to_string();
+    let mut source = read_source(file_path, !index_exists, !index_exists || degrees_missing, max_group, include_whole)?;
+    expected.content_hash = source.content_hash;
+
+    if let Some(existing) = existing_manifest {
+        if existing.content_hash != expected.content_hash {
+            // Same size and mtime but different contents
+            eprintln!("Index files for {} are stale (content hash {:016x} != {:016x}), rebuilding.",
+                      file_path, existing.content_hash, expected.content_hash);
+            index_exists = false;
+            degrees_missing = false;
+            source = read_source(file_path, true, true, max_group, include_whole)?;
+        }
+    }
+    let line_starts = source.line_starts;
+
+    // Written under temporary names and only renamed into place once complete
+    let fst_tmp_file = format!("{}.tmp", fst_file);
+    let postings_tmp_file = format!("{}.tmp", postings_file);
+    let degree_tmp_file = format!("{}.tmp", degree_file);
+    let mut build: Option<MapBuilder<io::BufWriter<File>>> = None;
+    if !index_exists {
+        let _ = fs::remove_file(&manifest_file);
+        let wtr = io::BufWriter::new(File::create(&fst_tmp_file).map_err(|e| IndexError::io(&fst_tmp_file, e))?);
+        build = Some(MapBuilder::new(wtr).map_err(|e| IndexError::fst(&fst_tmp_file, e))?);
+    }
+    let (stem_map, degree_map) = (source.stem_map, source.degree_map);
 
-    let mut result_index = FstIndex{
-        fst_values,
+    match build {
+        Some(mut build) => {
+            println!("Building fst");
+            let fst_start = Instant::now();
+            let postings_error = |e| IndexError::io(&postings_tmp_file, e);
+            let fst_error = |e| IndexError::fst(&fst_tmp_file, e);
+            let mut postings_writer = PostingsWriter::create(&postings_tmp_file).map_err(postings_error)?;
+            for (merged_counter, (stem, orig_line_vec)) in stem_map.iter().enumerate() {
+                postings_writer.push(orig_line_vec).map_err(postings_error)?;
+                build.insert(stem, merged_counter as u64).map_err(fst_error)?;
+            }
+            println!("Finished building fst: {} seconds", fst_start.elapsed().as_secs());
+            build.finish().map_err(fst_error)?;
+            postings_writer.finish().map_err(postings_error)?;
+            write_degrees(&degree_tmp_file, &degree_map)?;
+            fs::rename(&fst_tmp_file, &fst_file).map_err(|e| IndexError::io(&fst_file, e))?;
+            fs::rename(&postings_tmp_file, &postings_file).map_err(|e| IndexError::io(&postings_file, e))?;
+            fs::rename(&degree_tmp_file, &degree_file).map_err(|e| IndexError::io(&degree_file, e))?;
+            manifest::write_manifest(&manifest_file, &expected).map_err(|e| IndexError::io(&manifest_file, e))?;
+            println!("Finished writing fst: {} seconds (cumulative)", fst_start.elapsed().as_secs());
+        },
+        None => {
+            if degrees_missing {
+                write_degrees(&degree_tmp_file, &degree_map)?;
+                fs::rename(&degree_tmp_file, &degree_file).map_err(|e| IndexError::io(&degree_file, e))?;
+            }
+            // From now on converted files are checked against the source like any other
+            if legacy {
+                manifest::write_manifest(&manifest_file, &expected).map_err(|e| IndexError::io(&manifest_file, e))?;
+            }
+            eprintln!("Skipping fst write because file exists.");
+        }
+    }
+    let postings = postings::open_postings(&postings_file).map_err(|e| IndexError::io(&postings_file, e))?;
+    let degree_mmap = unsafe { Mmap::map(&File::open(&degree_file).map_err(|e| IndexError::io(&degree_file, e))?) }
+        .map_err(|e| IndexError::io(&degree_file, e))?;
+    let degrees = Map::new(degree_mmap).map_err(|e| IndexError::fst(&degree_file, e))?;
+    let fst_mmap = unsafe { Mmap::map(&File::open(&fst_file).map_err(|e| IndexError::io(&fst_file, e))?) }
+        .map_err(|e| IndexError::io(&fst_file, e))?;
+    let stems = Map::new(fst_mmap).map_err(|e| IndexError::fst(&fst_file, e))?;
+    let association_map = unsafe { Mmap::map(&File::open(file_path).map_err(|e| IndexError::io(file_path, e))?) }
+        .map_err(|e| IndexError::io(file_path, e))?;
+    Ok(FstIndex{
+        postings,
         line_starts,
         association_file,
+        association_map,
         fst_file,
-        max_group
-    };
+        stems,
+        degrees
+    })
+}
+
+// What one pass over an association file gathers, stems and degrees only when asked for
+struct SourcePass {
+    // Byte offsets of each line, followed by the file length
+    line_starts: Vec<u64>,
+    content_hash: u64,
+    // stem -> lines it was generated from
+    stem_map: BTreeMap<String, Vec<u64>>,
+    // article -> number of lines listing it
+    degree_map: BTreeMap<String, u64>
+}
+
+fn read_source(file_path: &str, stems: bool, degrees: bool, max_group: usize, include_whole: bool) -> Result<SourcePass, IndexError> {
+    let mut pass = SourcePass{line_starts: Vec::new(), content_hash: 0, stem_map: BTreeMap::new(), degree_map: BTreeMap::new()};
+    let mut hasher = ContentHasher::default();
+
     let mut counter: u64 = 0;
     let mut byte_counter: u64 = 0;
     let process_start = Instant::now();
-    if let Ok(lines) = read_lines(file_path) {
-        for line in lines {
-            if let Ok(entry) = line {
-                let mut mutable_bytes = entry.into_bytes();
-
-                result_index.line_starts.push(byte_counter);
-                byte_counter += (mutable_bytes.len() + 1) as u64; // + 1 for newline
-                if !index_exists {
-                    let v: Value = simd_json::serde::from_slice(&mut mutable_bytes).unwrap();
-                    let pair = v.as_array().unwrap();
-                    let title = pair[0].as_str().unwrap();
-                    // Generate stems from title
-                    let stems = stemmer::generate_stems(&title, max_group, include_whole);
-                    if stems.len() > 0 {
-                        // For each stem, insert into 
-                        for stem in stems {
-                            let stem_string = stem.to_string();
-                            let entry = stem_map.entry(stem_string).or_insert_with(Vec::new);
-                            entry.push(counter);
-                        }
-                    }
+    for line in open_lines(file_path)? {
+        let entry = line.map_err(|e| IndexError::line(file_path, counter as usize + 1, e))?;
+        hasher.update(entry.as_bytes());
+        hasher.update(b"\n");
+
+        pass.line_starts.push(byte_counter);
+        byte_counter += (entry.len() + 1) as u64; // + 1 for newline
+        if stems || degrees {
+            let (title, articles) = parse_association_line(file_path, counter as usize + 1, &entry)?;
+            if degrees {
+                for article in articles {
+                    *pass.degree_map.entry(article).or_default() += 1;
                 }
-                // Always increment counter otherwise
-                counter += 1;
-                if counter % 1000000 == 0 {
-                    println!("counter: {}", counter);
+            }
+            if stems {
+                // For each stem, insert into 
+                for stem in stemmer::generate_stems(&title, max_group, include_whole) {
+                    pass.stem_map.entry(stem).or_default().push(counter);
                 }
-            } else {
-                println!("Error reading line!");
-                return None;
             }
         }
+        // Always increment counter otherwise
+        counter += 1;
+        if counter % 1000000 == 0 {
+            println!("counter: {}", counter);
+        }
     }
     // Sentinel value so we can query ranges by i, i+1
-    result_index.line_starts.push(byte_counter);
+    pass.line_starts.push(byte_counter);
+    pass.content_hash = hasher.finish();
     println!("Finished gathering stemmed chunks in: {} seconds", process_start.elapsed().as_secs());
+    Ok(pass)
+}
 
-    if !index_exists {
-        let fst_write_ref = &mut fst_values_writer.unwrap();
-        match build {
-            Some(mut build) => {
-                println!("Building fst");
-                let fst_start = Instant::now();
-                let mut merged_counter = 0;
-                for (stem, orig_line_vec) in &stem_map {
-                    result_index.fst_values.push(orig_line_vec.to_vec());
-                    let mut line_vec_string = json!(orig_line_vec.to_vec()).to_string();
-                    line_vec_string += "\n";
-                    fst_write_ref.write_all(line_vec_string.as_bytes()).unwrap();
-                    build.insert(stem.to_string(), merged_counter).unwrap();
-                    merged_counter += 1;
+fn write_degrees(degree_file: &str, degree_map: &BTreeMap<String, u64>) -> Result<(), IndexError> {
+    let fst_error = |e| IndexError::fst(degree_file, e);
+    let wtr = io::BufWriter::new(File::create(degree_file).map_err(|e| IndexError::io(degree_file, e))?);
+    let mut build = MapBuilder::new(wtr).map_err(fst_error)?;
+    for (article, degree) in degree_map {
+        build.insert(article, *degree).map_err(fst_error)?;
+    }
+    build.finish().map_err(fst_error)
+}
+
+// <dir>/<prefix>_<file name>.<extension>, next to the association file
+fn side_file(file_path: &str, prefix: &str, extension: &str) -> String {
+    let path = Path::new(file_path);
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or(file_path);
+    let side_name = format!("{}_{}.{}", prefix, file_name, extension);
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.join(side_name).to_string_lossy().into_owned(),
+        _ => side_name
+    }
+}
+
+// The degree fst only depends on the source, so it can be rebuilt without the stems
+fn check_degree_file(degree_file: &str) -> Result<(), String> {
+    let degree_mmap = unsafe { Mmap::map(&File::open(degree_file).map_err(|e| format!("{}: {}", degree_file, e))?) }
+        .map_err(|e| e.to_string())?;
+    Map::new(degree_mmap).map_err(|e| format!("{}: {}", degree_file, e))?;
+    Ok(())
+}
+
+// Catches truncated or mismatched side files, every FST value must have a postings list
+fn check_side_files(fst_file: &str, postings_file: &str) -> Result<(), String> {
+    let mmap = unsafe { Mmap::map(&File::open(fst_file).map_err(|e| e.to_string())?) }
+        .map_err(|e| e.to_string())?;
+    let map = Map::new(mmap).map_err(|e| format!("{}: {}", fst_file, e))?;
+    let postings = postings::open_postings(postings_file).map_err(|e| e.to_string())?;
+    if map.len() != postings.len() {
+        return Err(format!("{} has {} stems but {} has {} lists", fst_file, map.len(), postings_file, postings.len()));
+    }
+    Ok(())
+}
+
+/**
+ * accessory_<file>.map stores one JSON array of line numbers per stem. Converted through
+ * a temporary file so an interrupted conversion never leaves a postings file behind, and
+ * renamed to accessory_<file>.map.converted once done.
+ */
+fn convert_legacy_values(legacy_values_file: &str, postings_file: &str) -> io::Result<()> {
+    let postings_tmp_file = format!("{}.tmp", postings_file);
+    let mut postings_writer = PostingsWriter::create(&postings_tmp_file)?;
+    for (line_num, entry) in read_lines(legacy_values_file)?.enumerate() {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", legacy_values_file, line_num + 1, message));
+        let v: Value = serde_json::from_str(&entry?).map_err(|e| invalid(e.to_string()))?;
+        let items = v.as_array().ok_or_else(|| invalid("expected an array of line numbers".to_string()))?;
+        let mut line_vec: Vec<u64> = Vec::with_capacity(items.len());
+        for item in items {
+            line_vec.push(item.as_u64().ok_or_else(|| invalid(format!("{} is not a line number", item)))?);
+        }
+        // Postings are delta encoded, older builders didn't always write lines in order
+        line_vec.sort_unstable();
+        line_vec.dedup();
+        postings_writer.push(&line_vec)?;
+    }
+    postings_writer.finish()?;
+    fs::rename(&postings_tmp_file, postings_file)?;
+    // Otherwise a later run missing its manifest would take the files for unconverted legacy ones again
+    fs::rename(legacy_values_file, format!("{}.converted", legacy_values_file))
+}
+
+impl FstIndex {
+    // Calls found(article, key) for every article on the lines listed under fst_value_index
+    fn for_each_posting<F: FnMut(ArticleId, ArticleId)>(&self, fst_value_index: u64, titles: &QueryTitles, found: &mut F) {
+        let lines = match self.postings.get(fst_value_index as usize) {
+            Ok(lines) => lines,
+            Err(e) => {
+                eprintln!("Skipping postings list {} of {}: {}", fst_value_index, self.fst_file, e);
+                return;
+            }
+        };
+        for orig_file_line in lines {
+            let line_num: usize = orig_file_line as usize;
+            // Get byte offset from line_offsets, a corrupt list can point past the last line
+            let (start_offset, end_offset) = match (self.line_starts.get(line_num), self.line_starts.get(line_num + 1)) {
+                (Some(start), Some(end)) => (*start as usize, *end as usize),
+                _ => continue
+            };
+
+            // A source that shrank since it was indexed would otherwise slice past the mapping
+            let line_bytes = match self.association_map.get(start_offset..end_offset) {
+                Some(line_bytes) => line_bytes,
+                None => continue
+            };
+            // Every line was parsed when the index was built, only a concurrent edit can break this
+            let parsed = std::str::from_utf8(line_bytes).ok()
+                .and_then(|entry| parse_association_line(&self.association_file, line_num + 1, entry).ok());
+            if let Some((key, articles)) = parsed {
+                let title = titles.id(&key);
+                for article in articles {
+                    found(titles.id(&article), title);
                 }
-                println!("Finished building fst: {} seconds", fst_start.elapsed().as_secs());
-                build.finish().unwrap();
-                fst_write_ref.flush().unwrap();
-                println!("Finished writing fst: {} seconds (cumulative)", fst_start.elapsed().as_secs());
-            },
-            None => {
-                eprintln!("Skipping fst write because file exists.");
             }
         }
-    } else {
-        // Load FST values from JSON
-        if let Ok(lines) = read_lines(fst_values_file) {
-            for line in lines {
-                if let Ok(entry) = line {
-                    let mut mutable_bytes = entry.into_bytes();
-                    let v: Value = simd_json::serde::from_slice(&mut mutable_bytes).unwrap();
-                    let mut line_vec: Vec<u64> = Vec::new();
-                    for item in v.as_array().unwrap() {
-                        line_vec.push(item.as_u64().unwrap());
-                    }
-                    result_index.fst_values.push(line_vec);
-                }
+    }
+
+    fn search_automaton<A: Automaton>(&self, automaton: A, max_keys: usize, titles: &QueryTitles) -> Result<HashMap<ArticleId, ArticleId>, String> {
+        // Collect the keys first, so a pattern matching half the index fails fast
+        let mut fst_values: Vec<u64> = Vec::new();
+        let mut stream = self.stems.search(automaton).into_stream();
+        while let Some((_, fst_value_index)) = stream.next() {
+            if fst_values.len() == max_keys {
+                return Err(format!("more than {} keys match, try a longer pattern", max_keys));
             }
+            fst_values.push(fst_value_index);
+        }
+        let mut result_map: HashMap<ArticleId, ArticleId> = HashMap::new();
+        for fst_value_index in fst_values {
+            self.for_each_posting(fst_value_index, titles, &mut |article, title| {
+                result_map.insert(article, title);
+            });
         }
+        Ok(result_map)
     }
-    return Some(result_index);
 }
 
-
 impl Searchable for FstIndex {
-    fn search(&self, term: &str, max_group: usize, include_whole: bool) -> HashMap<String, String> {
-        let index = &self;
-        let mmap = unsafe { Mmap::map(&File::open(&(index.fst_file)).unwrap()).unwrap() };
-        let map = Map::new(mmap).unwrap();
+    fn search(&self, term: &str, max_group: usize, include_whole: bool, titles: &QueryTitles) -> HashMap<ArticleId, ArticleId> {
+        let mut result_map: HashMap<ArticleId, ArticleId> = HashMap::new();
     
-        let association_file_map = unsafe { Mmap::map(&File::open(&(index.association_file)).unwrap()).unwrap() };
-    
-        let mut result_map: HashMap<String, String> = HashMap::new();
-    
-        let stems = stemmer::generate_stems(&term, max_group, include_whole);
+        let stems = stemmer::generate_stems(term, max_group, include_whole);
         for stem in stems {
-            match map.get(&stem) {
-                Some(fst_value_index) => {
-                    for orig_file_line in &(index.fst_values)[fst_value_index as usize] {
-                        let line_num: usize = *orig_file_line as usize;
-                        // Get byte offset from line_offsets
-                        let start_offset = (index.line_starts)[line_num] as usize;
-                        let end_offset = (index.line_starts)[line_num + 1] as usize;
-    
-                        let mut byte_vec: Vec<u8> = association_file_map[start_offset..end_offset].iter().cloned().collect();
-                        let v: Value = simd_json::serde::from_slice(&mut byte_vec[..]).unwrap();
-                        let pair = v.as_array().unwrap();
-                        let title = pair[0].as_str().unwrap(); // unused but might be good for filtering
-                        let article_array = pair[1].as_array().unwrap();
-                        for article in article_array {
-                            result_map.insert(article.to_string(), title.to_string());
-                        }
+            if let Some(fst_value_index) = self.stems.get(&stem) {
+                self.for_each_posting(fst_value_index, titles, &mut |article, title| {
+                    result_map.insert(article, title);
+                });
+            }
+        }
+        result_map
+    }
+
+    fn search_fuzzy(&self, term: &str, max_group: usize, include_whole: bool, distance: u32, titles: &QueryTitles) -> Result<HashMap<ArticleId, (ArticleId, u32)>, String> {
+        let mut result_map: HashMap<ArticleId, (ArticleId, u32)> = HashMap::new();
+        for stem in stemmer::generate_stems(term, max_group, include_whole) {
+            // Long stems blow the automaton's state limit, the shorter ones can still match
+            let automaton = match Levenshtein::new(&stem, distance) {
+                Ok(automaton) => automaton,
+                Err(e) => {
+                    eprintln!("Skipping fuzzy search for {}: {}", stem, e);
+                    continue;
+                }
+            };
+            let mut stream = self.stems.search(automaton).into_stream();
+            while let Some((key, fst_value_index)) = stream.next() {
+                // The automaton only says the key is close enough, keep the closest key per article
+                let edits = edit_distance(&stem, &String::from_utf8_lossy(key));
+                self.for_each_posting(fst_value_index, titles, &mut |article, title| {
+                    let closest = result_map.entry(article).or_insert((title, edits));
+                    if edits < closest.1 {
+                        *closest = (title, edits);
                     }
-                },
-                None => {}
+                });
             }
         }
-        return result_map;
+        Ok(result_map)
+    }
+
+    fn search_keys(&self, pattern: &KeyPattern, max_keys: usize, titles: &QueryTitles) -> Result<HashMap<ArticleId, ArticleId>, String> {
+        match pattern {
+            KeyPattern::Prefix(prefix) => self.search_automaton(Str::new(prefix).starts_with(), max_keys, titles),
+            KeyPattern::Pattern(letters) => self.search_automaton(letters.clone(), max_keys, titles)
+        }
+    }
+
+    fn in_degree(&self, article: ArticleId, titles: &QueryTitles) -> u64 {
+        self.degrees.get(titles.resolve(article).as_bytes()).unwrap_or(0)
+    }
+
+    fn num_lines(&self) -> u64 {
+        (self.line_starts.len() - 1) as u64
     }
 }
 
 impl Searchable for InMemoryIndex {
-    fn search(&self, term: &str, max_group: usize, include_whole: bool) -> HashMap<String, String> {
-        let mut result_map: HashMap<String, String> = HashMap::new();
-        let stems = stemmer::generate_stems(&term, max_group, include_whole);
+    fn search(&self, term: &str, max_group: usize, include_whole: bool, _titles: &QueryTitles) -> HashMap<ArticleId, ArticleId> {
+        let mut result_map: HashMap<ArticleId, ArticleId> = HashMap::new();
+        let stems = stemmer::generate_stems(term, max_group, include_whole);
         for stem in stems {
-            match self.index.get(&stem) {
-                Some(results) => {
-                    for line_index in results {
-                        let line_slice: &Vec<String> = &self.lines[*line_index];
-                        // Original item, it's added during index generation
-                        let orig_item = line_slice.first().unwrap();
-                        for i in 1..line_slice.len() {
-                            result_map.insert(line_slice[i].to_string(), orig_item.to_string());
-                        }
+            if let Some(results) = self.index.get(&stem) {
+                for line_index in results {
+                    let line_slice: &Vec<ArticleId> = &self.lines[*line_index];
+                    // Original item, it's added during index generation
+                    let orig_item = line_slice.first().unwrap();
+                    for article in &line_slice[1..] {
+                        result_map.insert(*article, *orig_item);
                     }
-                },
-                None => {}
+                }
             }
         }
-        return result_map;
+        result_map
+    }
+
+    fn in_degree(&self, article: ArticleId, _titles: &QueryTitles) -> u64 {
+        self.degrees.get(&article).copied().unwrap_or(0) as u64
+    }
+
+    fn num_lines(&self) -> u64 {
+        self.lines.len() as u64
     }
 }
 
 /**
- *
+ * Loads an association file fully into memory, mapping each stem to the lines it appears in
  */
-pub fn generate_inmemory_index(file_path: &str, max_group: usize, include_whole: bool) -> InMemoryIndex {
+pub fn generate_inmemory_index(file_path: &str, max_group: usize, include_whole: bool, titles: &TitleDict) -> Result<InMemoryIndex, IndexError> {
 
     let index: HashMap<String, Vec<usize>> = HashMap::new();
-    let lines: Vec<Vec<String>> = Vec::new();
-    let mut inmemory_index = InMemoryIndex{index, lines};
+    let lines: Vec<Vec<ArticleId>> = Vec::new();
+    let mut inmemory_index = InMemoryIndex{index, lines, degrees: HashMap::new()};
     let mut counter = 0;
     let process_start = Instant::now();
-    if let Ok(lines) = read_lines(file_path) {
-        for line in lines {
-            if let Ok(entry) = line {
-                let mut mutable_bytes = entry.into_bytes();
-                let v: Value = simd_json::serde::from_slice(&mut mutable_bytes).unwrap();
-                let pair = v.as_array().unwrap();
-                let title = pair[0].as_str().unwrap();
-                let article_array = pair[1].as_array().unwrap();
-                // Generate stems from title
-                let stems = stemmer::generate_stems(&title, max_group, include_whole);
-                if stems.len() == 0 {
-                    continue;
-                }
-                let mut article_vec = vec![title.to_string()];
-                for article in article_array.iter() {
-                    let article_string = article.as_str().unwrap();
-                    article_vec.push(article_string.to_string());
-                }
-                for stem in stems {
-                    let entry = inmemory_index.index.entry(stem.to_string()).or_insert_with(Vec::new);
-                    entry.push(counter);
-                }
-                inmemory_index.lines.push(article_vec);
-                counter += 1;
-                if counter % 1000000 == 0 {
-                    println!("counter: {}", counter);
-                }
-            }
+    for (line_index, line) in open_lines(file_path)?.enumerate() {
+        let entry = line.map_err(|e| IndexError::line(file_path, line_index + 1, e))?;
+        let (title, article_array) = parse_association_line(file_path, line_index + 1, &entry)?;
+        // Generate stems from title
+        let stems = stemmer::generate_stems(&title, max_group, include_whole);
+        if stems.is_empty() {
+            continue;
+        }
+        let mut article_vec = vec![titles.intern(&title)];
+        for article in article_array.iter() {
+            article_vec.push(titles.intern(article));
+        }
+        for stem in stems {
+            let entry = inmemory_index.index.entry(stem.to_string()).or_default();
+            entry.push(counter);
+        }
+        inmemory_index.lines.push(article_vec);
+        counter += 1;
+        if counter % 1000000 == 0 {
+            println!("counter: {}", counter);
         }
     }
+    inmemory_index.degrees = count_degrees(&inmemory_index.lines);
     println!("Finished: {} seconds", process_start.elapsed().as_secs());
-    return inmemory_index;
+    Ok(inmemory_index)
 }
 
 /**
  * Populates an index from a file with format:
  * - ["text", ["a", "bunch", "of", "article", "titles", "containing", "text"]]
  *
+ * Every (stem, line) pair is its own key in <file>.fst, a stem's lines are a range scan.
  */
-pub fn generate_stemmed_index(file_path: &str, max_group: usize) -> StemmedIndex {
+pub fn generate_stemmed_index(file_path: &str, max_group: usize, include_whole: bool, titles: &TitleDict) -> Result<StemmedIndex, IndexError> {
 
+    // Always rebuilt from the source, written under a temporary name so a running server's mapping stays intact
     let fst_file = format!("{}.{}", file_path, "fst");
-    let wtr = io::BufWriter::new(File::create(&fst_file).unwrap());
-    let mut build = MapBuilder::new(wtr).unwrap();
-    let orig_vec: Vec<Vec<String>> = Vec::new();
+    let fst_tmp_file = format!("{}.tmp", fst_file);
+    let fst_error = |e| IndexError::fst(&fst_tmp_file, e);
+    let wtr = io::BufWriter::new(File::create(&fst_tmp_file).map_err(|e| IndexError::io(&fst_tmp_file, e))?);
+    let mut build = MapBuilder::new(wtr).map_err(fst_error)?;
+    let mut orig_vec: Vec<Vec<ArticleId>> = Vec::new();
     let mut chunk_vec: Vec<StemChunk> = Vec::new();
-    let mut result_index = StemmedIndex{
-        fst_file,
-        max_group,
-        orig_vec
-    };
     let mut counter: u64 = 0;
     let process_start = Instant::now();
-    let file = File::open(file_path).unwrap();
-    let reader = io::BufReader::new(file);
-    for line in reader.lines() {
-        if let Ok(entry) = line {
-            let mut mutable_bytes = entry.into_bytes();
-            let v: Value = simd_json::serde::from_slice(&mut mutable_bytes).unwrap();
-            let pair = v.as_array().unwrap();
-            let title = pair[0].as_str().unwrap();
-            let article_array = pair[1].as_array().unwrap();
-            // Generate stems from title
-            let stems = stemmer::generate_stems(&title, max_group, false);
-            if stems.len() == 0 {
-                continue;
-            }
-            let mut article_vec = vec![title.to_string()];
-            for article in article_array.iter() {
-                let article_string = article.as_str().unwrap();
-                article_vec.push(article_string.to_string());
-            }
-            result_index.orig_vec.push(article_vec);
-            // For each stem, insert into 
-            for stem in stems {
-                let stem_string = stem.to_string();
-                chunk_vec.push(StemChunk{
-                    stem: stem_string,
-                    index: counter
-                });
-            }
-            counter += 1;
-            if counter % 1000000 == 0 {
-                println!("counter: {}", counter);
-            }
+    for (line_index, line) in open_lines(file_path)?.enumerate() {
+        let entry = line.map_err(|e| IndexError::line(file_path, line_index + 1, e))?;
+        let (title, article_array) = parse_association_line(file_path, line_index + 1, &entry)?;
+        // Generate stems from title
+        let stems = stemmer::generate_stems(&title, max_group, include_whole);
+        if stems.is_empty() {
+            continue;
+        }
+        let mut article_vec = vec![titles.intern(&title)];
+        for article in article_array.iter() {
+            article_vec.push(titles.intern(article));
+        }
+        orig_vec.push(article_vec);
+        // For each stem, insert into 
+        for stem in stems {
+            let stem_string = stem.to_string();
+            chunk_vec.push(StemChunk{
+                stem: stem_string,
+                index: counter
+            });
+        }
+        counter += 1;
+        if counter % 1000000 == 0 {
+            println!("counter: {}", counter);
         }
     }
     println!("Finished gathering stemmed chunks in: {} seconds", process_start.elapsed().as_secs());
     let sort_start = Instant::now();
     println!("Sorting now");
     chunk_vec.sort();
+    // The same stem can come up twice for one title
+    chunk_vec.dedup();
     println!("Finished sorting in: {} seconds", sort_start.elapsed().as_secs());
 
     println!("Building fst");
     let fst_start = Instant::now();
     for stem_chunk in chunk_vec {
-        build.insert(stem_chunk.stem, stem_chunk.index).unwrap();
+        build.insert(stem_chunk.key(), stem_chunk.index).map_err(fst_error)?;
     }
     println!("Finished building fst: {} seconds", fst_start.elapsed().as_secs());
-    build.finish().unwrap();
+    build.finish().map_err(fst_error)?;
+    fs::rename(&fst_tmp_file, &fst_file).map_err(|e| IndexError::io(&fst_file, e))?;
     println!("Finished writing fst: {} seconds (cumulative)", fst_start.elapsed().as_secs());
-    return result_index;
+
+    let mmap = unsafe { Mmap::map(&File::open(&fst_file).map_err(|e| IndexError::io(&fst_file, e))?) }
+        .map_err(|e| IndexError::io(&fst_file, e))?;
+    let map = Map::new(mmap).map_err(|e| IndexError::fst(&fst_file, e))?;
+    let degrees = count_degrees(&orig_vec);
+    Ok(StemmedIndex{
+        map,
+        orig_vec,
+        degrees
+    })
 }
 
+impl Searchable for StemmedIndex {
+    fn search(&self, term: &str, max_group: usize, include_whole: bool, _titles: &QueryTitles) -> HashMap<ArticleId, ArticleId> {
+        let mut result_map: HashMap<ArticleId, ArticleId> = HashMap::new();
+        let stems = stemmer::generate_stems(term, max_group, include_whole);
+        for stem in stems {
+            // Every key for this stem falls in [stem \0, stem \1)
+            let mut lower = stem.clone().into_bytes();
+            lower.push(0);
+            let mut upper = stem.into_bytes();
+            upper.push(1);
+            let mut stream = self.map.range().ge(&lower).lt(&upper).into_stream();
+            while let Some((_, line_index)) = stream.next() {
+                let line_slice: &Vec<ArticleId> = &self.orig_vec[line_index as usize];
+                let orig_item = line_slice.first().unwrap();
+                for article in &line_slice[1..] {
+                    result_map.insert(*article, *orig_item);
+                }
+            }
+        }
+        result_map
+    }
+
+    fn in_degree(&self, article: ArticleId, _titles: &QueryTitles) -> u64 {
+        self.degrees.get(&article).copied().unwrap_or(0) as u64
+    }
+
+    fn num_lines(&self) -> u64 {
+        self.orig_vec.len() as u64
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashSet;
+use std::time::Instant;
+
+use super::indexer;
+use super::indexer::IndexError;
+use super::titles::{ArticleId, TitleDict};
+
+/**
+ * Every distinct word or title in a file, for stages that look at spelling or sound
+ * rather than associations. Reads either format:
+ * - association files: ["text", ["a", "bunch", "of", "article", "titles"]], keys and articles
+ * - word lists: comma separated words, one group per line
+ */
+pub struct Lexicon {
+    words: Vec<ArticleId>
+}
+
+pub fn generate_lexicon(file_path: &str, titles: &TitleDict) -> Result<Lexicon, IndexError> {
+    let mut seen: HashSet<ArticleId> = HashSet::new();
+    let mut words: Vec<ArticleId> = Vec::new();
+    let mut add = |word: &str| {
+        let word = word.trim().to_lowercase();
+        if word.is_empty() {
+            return;
+        }
+        let id = titles.intern(&word);
+        if seen.insert(id) {
+            words.push(id);
+        }
+    };
+    let mut counter = 0;
+    let process_start = Instant::now();
+    for (line_index, line) in indexer::open_lines(file_path)?.enumerate() {
+        let entry = line.map_err(|e| IndexError::line(file_path, line_index + 1, e))?;
+        if entry.starts_with('[') {
+            let (key, articles) = indexer::parse_association_line(file_path, line_index + 1, &entry)?;
+            add(&key);
+            for article in &articles {
+                add(article);
+            }
+        } else {
+            for word in entry.split(',') {
+                add(word);
+            }
+        }
+        counter += 1;
+        if counter % 1000000 == 0 {
+            println!("counter: {}", counter);
+        }
+    }
+    println!("Finished: {} seconds", process_start.elapsed().as_secs());
+    Ok(Lexicon{words})
+}
+
+impl Lexicon {
+    pub fn words(&self) -> &[ArticleId] {
+        &self.words
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+}
@@ -2,12 +2,88 @@
 extern crate lazy_static;
 
 pub mod stemmer;
+pub mod titles;
 pub mod indexer;
 pub mod synonym_index;
+pub mod pronunciation_index;
+pub mod index_builder;
+pub mod binary_index;
+pub mod postings;
+pub mod manifest;
+pub mod config;
+pub mod stages;
+pub mod lexicon;
+pub mod anagram;
+pub mod phonetic;
+pub mod substring;
+pub mod pattern;
+pub mod transforms;
+pub mod phrases;
+pub mod scoring;
+pub mod session;
+pub mod http;
+pub mod query_error;
+pub mod budget;
+pub mod pool;
 
 #[cfg(test)]
 mod tests {
     use super::stemmer;
+    use super::index_builder;
+    use super::binary_index;
+    use super::postings;
+    use super::manifest;
+    use super::config;
+    use super::stages;
+    use super::lexicon;
+    use super::anagram;
+    use super::phonetic;
+    use super::pronunciation_index;
+    use super::substring;
+    use super::pattern;
+    use super::transforms;
+    use super::phrases;
+    use super::scoring;
+    use super::session;
+    use super::http;
+    use super::query_error::QueryError;
+    use super::budget::{Budget, Stop};
+    use super::pool::WorkerPool;
+    use super::titles::{QueryTitles, TitleDict};
+    use std::convert::TryInto;
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use super::indexer;
+    use super::indexer::{IndexError, Searchable};
+    use super::synonym_index;
+
+    // Scratch directory named after the test, removed again when dropped (also when the test fails)
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+            // Left behind by a killed run
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
 
     fn vec_compare(va: &[String], vb: &[String]) -> bool {
         (va.len() == vb.len()) &&
@@ -20,7 +96,7 @@ mod tests {
     fn stemmer_basic_test() {
         let mut stems = stemmer::generate_stems("sup-cat what hi", 3, false);
         eprintln!("{:?}", stems);
-        let expected_list = vec!["supcat", "what", "hi", "supcat what", "what hi", "supcat what hi"];
+        let expected_list = ["supcat", "what", "hi", "supcat what", "what hi", "supcat what hi"];
         let mut expected: Vec<String> = expected_list.iter().map(|x| x.to_string()).collect();
         stems.sort();
         expected.sort();
@@ -31,7 +107,7 @@ mod tests {
     fn stemmer_less_than_k() {
         let mut stems = stemmer::generate_stems("sup-cat what hi", 9, false);
         eprintln!("{:?}", stems);
-        let expected_list = vec!["supcat", "what", "hi", "supcat what", "what hi", "supcat what hi"];
+        let expected_list = ["supcat", "what", "hi", "supcat what", "what hi", "supcat what hi"];
         let mut expected: Vec<String> = expected_list.iter().map(|x| x.to_string()).collect();
         stems.sort();
         expected.sort();
@@ -42,7 +118,7 @@ mod tests {
     fn stemmer_repeated_whitespace() {
         let mut stems = stemmer::generate_stems("hello      there", 2, false);
         eprintln!("{:?}", stems);
-        let expected_list = vec!["hello", "there", "hello there"];
+        let expected_list = ["hello", "there", "hello there"];
         let mut expected: Vec<String> = expected_list.iter().map(|x| x.to_string()).collect();
         stems.sort();
         expected.sort();
@@ -53,10 +129,828 @@ mod tests {
     fn stemmer_norm_lower() {
         let mut stems = stemmer::generate_stems("HeLlO -TheRe-", 3, false);
         eprintln!("{:?}", stems);
-        let expected_list = vec!["hello", "there", "hello there"];
+        let expected_list = ["hello", "there", "hello there"];
         let mut expected: Vec<String> = expected_list.iter().map(|x| x.to_string()).collect();
         stems.sort();
         expected.sort();
         assert!(vec_compare(&(expected[..]), &stems));
     }
+
+    #[test]
+    fn index_builder_extracts_lists_and_tables() {
+        let page = "See [[Urban Cowboy|the film]] and [[Drugstore Cowboy]].\n* some list item\n* [[Linked]] item\n{|\n|-\n| Brick || Mortar\n|}";
+        let extraction = index_builder::extract_page(page);
+        assert_eq!(extraction.list_items, vec!["some list item"]);
+        assert_eq!(extraction.table_items, vec!["brick", "mortar"]);
+    }
+
+    #[test]
+    fn index_builder_merges_spilled_runs() {
+        let dir = TempDir::new("index_builder_test");
+        let condensed = dir.join("condensed.csv");
+        let records = [
+            ("Urban Cowboy", "[[Cowboy]]\n{|\n|-\n| Cowboy || Film\n|}\n* a list entry"),
+            ("Drugstore Cowboy", "[[Film]]\n{|\n|-\n| cowboy\n|}"),
+            ("Foreign", "{{Not English}} [[Cowboy]]"),
+        ];
+        let mut contents = String::new();
+        for (title, page) in records.iter() {
+            contents += &format!("{}\n[]\n{}\n", serde_json::json!(title), serde_json::json!(page));
+        }
+        std::fs::write(&condensed, contents).unwrap();
+        let norm = dir.join("norm.txt");
+        let table = dir.join("table.txt");
+        // Tiny buffer forces several runs through the merge
+        let stats = index_builder::build_association_indexes(
+            condensed.to_str().unwrap(), norm.to_str().unwrap(), table.to_str().unwrap(), 1).unwrap();
+        assert_eq!(stats.records, 3);
+        assert_eq!(stats.skipped, 1);
+        let norm_lines = std::fs::read_to_string(&norm).unwrap();
+        assert_eq!(norm_lines, "[\"cowboy\",[\"drugstore cowboy\",\"urban cowboy\"]]\n[\"film\",[\"urban cowboy\"]]\n");
+        let table_lines = std::fs::read_to_string(&table).unwrap();
+        assert!(table_lines.starts_with("[\"a list entry\",[\"urban cowboy\"]]\n"));
+        assert_eq!(stats.table_entries, 3);
+    }
+
+    #[test]
+    fn binary_index_round_trip() {
+        let dir = TempDir::new("binary_index_test");
+        let association = dir.join("association.txt");
+        std::fs::write(&association, concat!(
+            "[\"drugstore cowboy\",[\"list of drama films of the 1980s\",\"gus van sant\"]]\n",
+            "[\"urban cowboy\",[\"list of drama films of the 1980s\"]]\n",
+            "[\"urban legend\",[\"folklore\"]]\n")).unwrap();
+        let binary = dir.join("association.txt.bin");
+        let stats = binary_index::write_binary_index(association.to_str().unwrap(), binary.to_str().unwrap(), 1, false).unwrap();
+        assert_eq!(stats.entries, 3);
+        // 3 keys + 3 distinct articles
+        assert_eq!(stats.strings, 6);
+        let titles = Arc::new(TitleDict::new());
+        let query_titles = QueryTitles::new(titles.clone());
+        let index = binary_index::open_binary_index(binary.to_str().unwrap()).unwrap();
+        let results = index.search("cowboy", 1, false, &query_titles);
+        assert_eq!(results.len(), 2);
+        assert_eq!(&*query_titles.resolve(results[&query_titles.get("gus van sant").unwrap()]), "drugstore cowboy");
+        assert!(results.contains_key(&query_titles.get("list of drama films of the 1980s").unwrap()));
+        let urban = index.search("urban", 1, false, &query_titles);
+        assert_eq!(urban[&query_titles.get("folklore").unwrap()], query_titles.get("urban legend").unwrap());
+        assert!(index.search("missing", 1, false, &query_titles).is_empty());
+        // Searching doesn't grow the shared dictionary, but already interned titles keep their ids
+        assert!(titles.is_empty());
+        let folklore = titles.intern("folklore");
+        let urban = index.search("urban", 1, false, &QueryTitles::new(titles.clone()));
+        assert!(urban.contains_key(&folklore));
+        assert!(!dir.join("association.txt.bin.tmp").exists());
+        // A cut off file is refused rather than read past its end
+        let bytes = std::fs::read(&binary).unwrap();
+        let truncated = dir.join("truncated.bin");
+        std::fs::write(&truncated, &bytes[..bytes.len() - 10]).unwrap();
+        assert!(binary_index::open_binary_index(truncated.to_str().unwrap()).is_err());
+        // So are ids past the strings and strings that aren't utf8
+        let corrupt = dir.join("corrupt.bin");
+        let header_u64 = |pos: usize| u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap()) as usize;
+        let (string_data_pos, entry_data_pos) = (header_u64(56), header_u64(72));
+        let mut bad_id = bytes.clone();
+        bad_id[entry_data_pos..entry_data_pos + 4].copy_from_slice(&6u32.to_le_bytes());
+        std::fs::write(&corrupt, &bad_id).unwrap();
+        assert!(binary_index::open_binary_index(corrupt.to_str().unwrap()).is_err());
+        let mut bad_string = bytes.clone();
+        bad_string[string_data_pos] = 0xff;
+        std::fs::write(&corrupt, &bad_string).unwrap();
+        assert!(binary_index::open_binary_index(corrupt.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn binary_index_stamp_mismatch() {
+        let dir = TempDir::new("binary_stamp_test");
+        let association = dir.join("association.txt");
+        std::fs::write(&association, "[\"urban cowboy\",[\"folklore\"]]\n").unwrap();
+        let (path, binary) = (association.to_str().unwrap(), dir.join("association.txt.bin"));
+        binary_index::write_binary_index(path, binary.to_str().unwrap(), 1, false).unwrap();
+        let index = binary_index::open_binary_index(binary.to_str().unwrap()).unwrap();
+        assert_eq!(index.stamp_mismatch(path, 1, false), None);
+        assert!(index.stamp_mismatch(path, 2, false).is_some());
+        assert!(index.stamp_mismatch(path, 1, true).is_some());
+        std::fs::write(&association, "[\"urban legend\",[\"folklore\"]]\n[\"urban cowboy\",[\"folklore\"]]\n").unwrap();
+        assert!(index.stamp_mismatch(path, 1, false).is_some());
+    }
+
+    #[test]
+    fn postings_round_trip() {
+        let dir = TempDir::new("postings_test");
+        let path = dir.join("postings.bin");
+        let lists: Vec<Vec<u64>> = vec![vec![0, 1, 2], vec![], vec![5, 300, 70000, 1 << 40]];
+        let mut writer = postings::PostingsWriter::create(path.to_str().unwrap()).unwrap();
+        for list in &lists {
+            writer.push(list).unwrap();
+        }
+        writer.finish().unwrap();
+        let postings = postings::open_postings(path.to_str().unwrap()).unwrap();
+        assert_eq!(postings.len(), 3);
+        for (i, list) in lists.iter().enumerate() {
+            assert_eq!(&postings.get(i).unwrap(), list);
+        }
+        assert!(postings.get(3).is_err());
+        // Deltas would underflow
+        let mut writer = postings::PostingsWriter::create(path.to_str().unwrap()).unwrap();
+        assert!(writer.push(&[3, 1]).is_err());
+        assert!(writer.push(&[2, 2]).is_err());
+    }
+
+    #[test]
+    fn postings_corrupt_lists_fail() {
+        let dir = TempDir::new("postings_corrupt_test");
+        let path = dir.join("postings.bin");
+        let mut writer = postings::PostingsWriter::create(path.to_str().unwrap()).unwrap();
+        writer.push(&[1, 2]).unwrap();
+        writer.push(&[u64::MAX]).unwrap();
+        writer.finish().unwrap();
+        // 2 bytes for the first list, then 10 bytes of continuation bits for the second
+        let mut bytes = std::fs::read(&path).unwrap();
+        for byte in &mut bytes[26..36] {
+            *byte = 0xff;
+        }
+        std::fs::write(&path, &bytes).unwrap();
+        let postings = postings::open_postings(path.to_str().unwrap()).unwrap();
+        assert_eq!(postings.get(0).unwrap(), vec![1, 2]);
+        assert!(postings.get(1).is_err());
+        assert!(postings::decode_varint(&[0x80; 3]).is_err());
+    }
+
+    #[test]
+    fn title_dict_interns_once() {
+        let titles = TitleDict::new();
+        let cowboy = titles.intern("urban cowboy");
+        assert_eq!(titles.intern("drugstore cowboy"), cowboy + 1);
+        assert_eq!(titles.intern("urban cowboy"), cowboy);
+        assert_eq!(&*titles.resolve(cowboy), "urban cowboy");
+        assert_eq!(titles.get("missing"), None);
+        assert_eq!(titles.len(), 2);
+    }
+
+    #[test]
+    fn manifest_round_trip_and_mismatch() {
+        let dir = TempDir::new("manifest_test");
+        let manifest_path = dir.join("manifest.json");
+        let manifest_path = manifest_path.to_str().unwrap();
+        let mut hasher = manifest::ContentHasher::default();
+        hasher.update(b"[\"cowboy\", [\"urban cowboy\"]]\n");
+        let written = manifest::IndexManifest{
+            source_size: 31,
+            source_mtime: 1600000000,
+            content_hash: hasher.finish(),
+            builder_version: manifest::BUILDER_VERSION,
+            max_group: 2,
+            include_whole: true
+        };
+        assert_eq!(manifest::read_manifest(manifest_path).unwrap(), None);
+        manifest::write_manifest(manifest_path, &written).unwrap();
+        let read = manifest::read_manifest(manifest_path).unwrap().unwrap();
+        assert_eq!(read, written);
+        assert_eq!(read.stamp_mismatch(&written), None);
+
+        let mut expected = written.clone();
+        expected.source_size = 32;
+        assert!(read.stamp_mismatch(&expected).unwrap().contains("source size"));
+        expected = written.clone();
+        expected.include_whole = false;
+        assert!(read.stamp_mismatch(&expected).unwrap().contains("include_whole"));
+    }
+
+    #[test]
+    fn index_loading_reports_file_and_line() {
+        let dir = TempDir::new("index_error_test");
+        let titles = TitleDict::new();
+        let missing = dir.join("missing.txt");
+        match indexer::generate_inmemory_index(missing.to_str().unwrap(), 1, false, &titles) {
+            Err(IndexError::Missing{file}) => assert_eq!(file, missing.to_str().unwrap()),
+            _ => panic!("expected a missing file error")
+        }
+        assert!(matches!(synonym_index::generate_synonym_index(missing.to_str().unwrap(), &titles),
+                         Err(IndexError::Missing{..})));
+
+        let malformed = dir.join("malformed.txt");
+        std::fs::write(&malformed, "[\"urban cowboy\",[\"folklore\"]]\n[\"urban legend\",[\"folk\n").unwrap();
+        match indexer::generate_inmemory_index(malformed.to_str().unwrap(), 1, false, &titles) {
+            Err(IndexError::Malformed{line, ..}) => assert_eq!(line, 2),
+            _ => panic!("expected a malformed line error")
+        }
+        let wrong_shape = dir.join("wrong_shape.txt");
+        std::fs::write(&wrong_shape, "[\"urban cowboy\",[\"folklore\"]]\n[\"a\",\"b\"]\n[\"c\",[]]\n").unwrap();
+        let error = indexer::generate_inmemory_index(wrong_shape.to_str().unwrap(), 1, false, &titles).err().unwrap();
+        assert!(matches!(error, IndexError::Shape{line: 2, ..}));
+        assert!(error.to_string().starts_with(&format!("{}:2:", wrong_shape.to_str().unwrap())));
+    }
+
+    #[test]
+    fn fst_index_rebuilds_when_source_changes() {
+        let dir = TempDir::new("fst_index_test");
+        let association = dir.join("association.txt");
+        std::fs::write(&association, "[\"urban cowboy\",[\"folklore\"]]\n").unwrap();
+        let path = association.to_str().unwrap();
+        let titles = QueryTitles::new(Arc::new(TitleDict::new()));
+        let index = indexer::generate_fst_index(path, 1, false).unwrap();
+        assert_eq!(index.search("cowboy", 1, false, &titles).len(), 1);
+        let manifest_path = dir.join("manifest_association.txt.json");
+        let manifest_path = manifest_path.to_str().unwrap();
+        let first = manifest::read_manifest(manifest_path).unwrap().unwrap();
+
+        // Same length so only the content hash can tell the difference
+        std::fs::write(&association, "[\"urban legend\",[\"folklore\"]]\n").unwrap();
+        let index = indexer::generate_fst_index(path, 1, false).unwrap();
+        assert!(index.search("cowboy", 1, false, &titles).is_empty());
+        assert_eq!(index.search("legend", 1, false, &titles).len(), 1);
+        let second = manifest::read_manifest(manifest_path).unwrap().unwrap();
+        assert_ne!(first.content_hash, second.content_hash);
+    }
+
+    #[test]
+    fn fst_index_reuses_legacy_files() {
+        let dir = TempDir::new("legacy_index_test");
+        let association = dir.join("association.txt");
+        std::fs::write(&association, "[\"urban cowboy\",[\"folklore\"]]\n[\"drugstore cowboy\",[\"gus van sant\"]]\n").unwrap();
+        // What the old builder left behind: stem -> ordinal, and the lines for each ordinal as JSON.
+        // A rebuild wouldn't have "legacy" as a stem, so finding it shows the converted files were kept
+        let mut build = fst::MapBuilder::new(std::fs::File::create(dir.join("fst_association.txt.fst")).unwrap()).unwrap();
+        for (ordinal, stem) in ["cowboy", "drugstore", "legacy", "urban"].iter().enumerate() {
+            build.insert(stem, ordinal as u64).unwrap();
+        }
+        build.finish().unwrap();
+        // Old files didn't always list lines in order, or only once
+        std::fs::write(dir.join("accessory_association.txt.map"), "[1,0,1]\n[1]\n[0]\n[0]\n").unwrap();
+        let path = association.to_str().unwrap();
+        let titles = QueryTitles::new(Arc::new(TitleDict::new()));
+        for _ in 0..2 {
+            let index = indexer::generate_fst_index(path, 1, false).unwrap();
+            assert_eq!(index.search("cowboy", 1, false, &titles).len(), 2);
+            assert_eq!(index.search("legacy", 1, false, &titles).len(), 1);
+            assert_eq!(index.in_degree(titles.get("folklore").unwrap(), &titles), 1);
+            // Converted once, then checked against the source through the manifest
+            assert!(dir.join("manifest_association.txt.json").exists());
+            assert!(!dir.join("accessory_association.txt.map").exists());
+            assert!(dir.join("accessory_association.txt.map.converted").exists());
+        }
+        assert!(dir.join("degree_association.txt.fst").exists());
+
+        // A changed source now rebuilds them like any other index
+        std::fs::write(&association, "[\"urban cowboy\",[\"folklore\"]]\n").unwrap();
+        let index = indexer::generate_fst_index(path, 1, false).unwrap();
+        assert!(index.search("legacy", 1, false, &titles).is_empty());
+        assert_eq!(index.search("cowboy", 1, false, &titles).len(), 1);
+    }
+
+    #[test]
+    fn stemmed_index_matches_other_backends() {
+        let dir = TempDir::new("stemmed_index_test");
+        let association = dir.join("association.txt");
+        // "cowboy" is a stem of several lines, which a single valued fst can't hold
+        std::fs::write(&association, concat!(
+            "[\"drugstore cowboy\",[\"gus van sant\"]]\n",
+            "[\"urban cowboy\",[\"list of drama films of the 1980s\"]]\n",
+            "[\"cowboy cowboy\",[\"repeats\"]]\n",
+            "[\"urban legend\",[\"folklore\"]]\n")).unwrap();
+        let path = association.to_str().unwrap();
+        let titles = Arc::new(TitleDict::new());
+        let stemmed = indexer::generate_stemmed_index(path, 1, false, &titles).unwrap();
+        let in_memory = indexer::generate_inmemory_index(path, 1, false, &titles).unwrap();
+        let fst = indexer::generate_fst_index(path, 1, false).unwrap();
+        let query_titles = QueryTitles::new(titles.clone());
+        for term in &["cowboy", "urban", "urban cowboy", "missing"] {
+            let results = stemmed.search(term, 1, false, &query_titles);
+            assert_eq!(results, in_memory.search(term, 1, false, &query_titles));
+            assert_eq!(results, fst.search(term, 1, false, &query_titles));
+        }
+        assert_eq!(stemmed.search("cowboy", 1, false, &query_titles).len(), 3);
+        assert!(dir.join("association.txt.fst").exists());
+        assert!(!dir.join("association.txt.fst.tmp").exists());
+    }
+
+    #[test]
+    fn config_parses_and_rejects_bad_indexes() {
+        let v = serde_json::json!({"indexes": [
+            {"name": "norm", "path": "big_norm_index.txt", "backend": "in-memory", "include_whole": true, "stages": ["WikiArticleStem"]},
+            {"name": "synonyms", "path": "moby_words.txt", "backend": "synonym", "stages": ["Synonym"]}
+        ]});
+        let parsed = config::ServerConfig::from_json(&v).unwrap();
+        assert_eq!(parsed.indexes.len(), 2);
+        assert_eq!(parsed.indexes[0].backend, config::Backend::InMemory);
+        assert_eq!(parsed.indexes[0].max_group, 1);
+        assert!(parsed.indexes[0].include_whole);
+        assert_eq!(parsed.indexes[1].stages, vec!["Synonym"]);
+
+        let bad_backend = serde_json::json!({"indexes": [{"path": "a.txt", "backend": "btree", "stages": []}]});
+        assert!(config::ServerConfig::from_json(&bad_backend).unwrap_err().contains("unknown backend"));
+        let duplicate = serde_json::json!({"indexes": [
+            {"name": "a", "path": "a.txt", "backend": "fst", "stages": []},
+            {"name": "a", "path": "b.txt", "backend": "fst", "stages": []}
+        ]});
+        assert!(config::ServerConfig::from_json(&duplicate).is_err());
+        // The default config round trips through its own format
+        let default_config = config::ServerConfig::default();
+        let round_trip = config::ServerConfig::from_json(&default_config.to_json()).unwrap();
+        assert_eq!(round_trip.indexes.len(), default_config.indexes.len());
+        assert_eq!((round_trip.bind_address.as_str(), round_trip.workers), (config::DEFAULT_BIND_ADDRESS, config::DEFAULT_WORKERS));
+        let server = serde_json::json!({"indexes": [], "bind_address": "0.0.0.0", "workers": 8, "queue_size": 0});
+        let parsed = config::ServerConfig::from_json(&server).unwrap();
+        assert_eq!((parsed.bind_address.as_str(), parsed.workers, parsed.queue_size), ("0.0.0.0", 8, 0));
+        assert!(config::ServerConfig::from_json(&serde_json::json!({"indexes": [], "workers": 0})).is_err());
+    }
+
+    #[test]
+    fn stage_registry_serves_loaded_indexes() {
+        let dir = TempDir::new("stage_registry_test");
+        let words = dir.join("words.txt");
+        std::fs::write(&words, "cowboy,wrangler,drover\n").unwrap();
+        let association = dir.join("association.txt");
+        std::fs::write(&association, "[\"urban cowboy\",[\"folklore\"]]\n").unwrap();
+        let titles = Arc::new(TitleDict::new());
+        let list = Arc::new(synonym_index::generate_synonym_index(words.to_str().unwrap(), &titles).unwrap());
+        let index: stages::SharedIndex = Arc::new(indexer::generate_inmemory_index(association.to_str().unwrap(), 1, false, &titles).unwrap());
+
+        let mut sources = stages::StageSources::default();
+        sources.associations.insert("WikiAllStem".to_string(), vec![index]);
+        sources.word_lists.insert("Synonym".to_string(), vec![list]);
+        let mut registry = stages::StageRegistry::new();
+        registry.register_builtins(sources, &titles);
+        assert_eq!(registry.names(), vec!["Synonym", "WikiAllStem"]);
+        assert!(registry.get("Homophone").is_none());
+
+        let terms = vec!["cowboy".to_string()];
+        let budget = Budget::unlimited();
+        let interned = titles.len();
+        let titles = QueryTitles::new(titles);
+        let synonyms = registry.get("Synonym").unwrap().expand_initial(&terms, &titles, &budget).unwrap();
+        assert_eq!(synonyms["cowboy"].len(), 3);
+        let wiki = registry.get("WikiAllStem").unwrap();
+        let found = wiki.expand_initial(&terms, &titles, &budget).unwrap();
+        assert!(found["cowboy"].contains_key(&titles.get("folklore").unwrap()));
+        // Only usable as a first stage
+        assert!(wiki.expand(&synonyms, &titles, &budget).is_err());
+        assert!(budget.stopped().is_none());
+
+        // Stages give up once the query is out of budget
+        let small = Budget::new(0, None);
+        assert!(wiki.expand_initial(&[terms[0].clone(), terms[0].clone()], &titles, &small).is_err());
+        assert_eq!(small.stopped(), Some(Stop::TooLarge(found["cowboy"].len())));
+        let cancelled = Budget::unlimited();
+        cancelled.token().cancel();
+        assert!(wiki.expand_initial(&terms, &titles, &cancelled).is_err());
+        assert_eq!(cancelled.stopped(), Some(Stop::Cancelled));
+        // Query terms only get query local ids
+        assert_eq!(titles.shared().len(), interned);
+        let expired = Budget::new(usize::MAX, Some(std::time::Duration::from_millis(0)));
+        assert_eq!(expired.check(0).unwrap_err(), "query timed out");
+    }
+
+    #[test]
+    fn anagram_index_finds_same_letters_and_one_off() {
+        let dir = TempDir::new("anagram_test");
+        let words = dir.join("words.txt");
+        std::fs::write(&words, "listen,silent,enlist\ndormitory,dirty room\nlistens,lists\n").unwrap();
+        let association = dir.join("association.txt");
+        std::fs::write(&association, "[\"tinsel\",[\"Listen (song)\"]]\n").unwrap();
+        let titles = TitleDict::new();
+        let lexicons = vec![
+            Arc::new(lexicon::generate_lexicon(words.to_str().unwrap(), &titles).unwrap()),
+            Arc::new(lexicon::generate_lexicon(association.to_str().unwrap(), &titles).unwrap())
+        ];
+        assert_eq!(lexicons[1].len(), 2);
+        assert_eq!(anagram::letter_signature("Dirty Room!"), anagram::letter_signature("dormitory"));
+        let index = anagram::AnagramIndex::new(&lexicons, &titles);
+        let names = |ids: Vec<u32>| {
+            let mut names: Vec<String> = ids.iter().map(|id| titles.resolve(*id).to_string()).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(index.search("Inlets", 0)), vec!["enlist", "listen", "silent", "tinsel"]);
+        assert_eq!(names(index.search("dormitory", 0)), vec!["dirty room", "dormitory"]);
+        assert_eq!(names(index.search("silent", 1)), vec!["listens"]);
+        assert_eq!(names(index.search("lists", -1)), Vec::<String>::new());
+        assert_eq!(names(index.search("listens", -1)), vec!["enlist", "listen", "silent", "tinsel"]);
+    }
+
+    #[test]
+    fn phonetic_codes_match_sound_alikes() {
+        assert_eq!(phonetic::metaphone("knight"), phonetic::metaphone("night"));
+        assert_eq!(phonetic::metaphone("Thumb"), "0M");
+        assert_eq!(phonetic::metaphone("phone"), phonetic::metaphone("fone"));
+        assert_eq!(phonetic::phonetic_code("ice cream"), phonetic::phonetic_code("I scream"));
+        assert_ne!(phonetic::metaphone("cat"), phonetic::metaphone("dog"));
+
+        let dir = TempDir::new("phonetic_test");
+        let words = dir.join("words.txt");
+        std::fs::write(&words, "night,knight,nite\nnights,dog\n").unwrap();
+        let titles = TitleDict::new();
+        let lexicons = vec![Arc::new(lexicon::generate_lexicon(words.to_str().unwrap(), &titles).unwrap())];
+        let index = phonetic::PhoneticIndex::new(&lexicons, &titles);
+        let mut results: Vec<(String, f64)> = index.search("Knite").into_iter()
+            .map(|(id, weight)| (titles.resolve(id).to_string(), weight))
+            .collect();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(results, vec![("knight".to_string(), 1.0), ("night".to_string(), 1.0),
+                                 ("nights".to_string(), phonetic::NEAR_WEIGHT), ("nite".to_string(), 1.0)]);
+    }
+
+    #[test]
+    fn pronunciation_index_finds_homophones_and_rhymes() {
+        let dir = TempDir::new("pronunciation_test");
+        let dict = dir.join("cmudict.txt");
+        std::fs::write(&dict, concat!(
+            ";;; comment\n",
+            "KNIGHT  N AY1 T\n",
+            "NIGHT  N AY1 T\n",
+            "LIGHT  L AY1 T\n",
+            "DELIGHT  D IH0 L AY1 T\n",
+            "READ  R IY1 D\n",
+            "READ(1)  R EH1 D\n",
+            "RED  R EH1 D\n",
+            "REED  R IY1 D\n")).unwrap();
+        let titles = TitleDict::new();
+        let index = pronunciation_index::generate_pronunciation_index(dict.to_str().unwrap(), &titles).unwrap();
+        assert_eq!(index.len(), 7);
+        let names = |ids: Vec<u32>| {
+            let mut names: Vec<String> = ids.iter().map(|id| titles.resolve(*id).to_string()).collect();
+            names.sort();
+            names
+        };
+        let id = |word: &str| titles.get(word).unwrap();
+        assert_eq!(names(index.homophones(id("knight"))), vec!["knight", "night"]);
+        // Both pronunciations of read count
+        assert_eq!(names(index.homophones(id("read"))), vec!["read", "red", "reed"]);
+        assert_eq!(names(index.rhymes(id("light"))), vec!["delight", "knight", "night"]);
+        assert_eq!(pronunciation_index::rhyme_key(&["D", "IH0", "L", "AY1", "T"]), "AY T");
+
+        std::fs::write(&dict, "KNIGHT  N AY1 T\nBROKEN\n").unwrap();
+        assert!(matches!(pronunciation_index::generate_pronunciation_index(dict.to_str().unwrap(), &titles),
+                         Err(IndexError::Shape{line: 2, ..})));
+    }
+
+    #[test]
+    fn substring_index_finds_hidden_words() {
+        let dir = TempDir::new("substring_test");
+        let words = dir.join("words.txt");
+        std::fs::write(&words, "scarpet,Oscar Pet,carpet\nscar,pets\n").unwrap();
+        let titles = TitleDict::new();
+        let lexicons = vec![Arc::new(lexicon::generate_lexicon(words.to_str().unwrap(), &titles).unwrap())];
+        let index = substring::SubstringIndex::new(&lexicons, &titles);
+        assert_eq!(index.len(), 5);
+        let mut found: Vec<(String, u32)> = index.search("Car").into_iter().map(|(id, position)| (titles.resolve(id).to_string(), position)).collect();
+        found.sort();
+        assert_eq!(found, vec![("carpet".to_string(), 0), ("oscar pet".to_string(), 2), ("scar".to_string(), 1), ("scarpet".to_string(), 1)]);
+        // Across the word boundary
+        let across: Vec<String> = index.search("rpe").into_iter().map(|(id, _)| titles.resolve(id).to_string()).collect();
+        assert_eq!(across.len(), 3);
+        assert!(index.search("xyz").is_empty());
+        assert_eq!(substring::highlight("oscar pet", 4, 3), "oscaR PEt");
+    }
+
+    #[test]
+    fn fst_index_fuzzy_search_reports_edits() {
+        assert_eq!(indexer::edit_distance("cot", "cart"), 2);
+        assert_eq!(indexer::edit_distance("", "cat"), 3);
+        let dir = TempDir::new("fuzzy_test");
+        let association = dir.join("association.txt");
+        std::fs::write(&association, "[\"cat\",[\"felix\"]]\n[\"cart\",[\"horse\"]]\n").unwrap();
+        let path = association.to_str().unwrap();
+        let titles = QueryTitles::new(Arc::new(TitleDict::new()));
+        let index = indexer::generate_fst_index(path, 1, false).unwrap();
+        let one_edit = index.search_fuzzy("cot", 1, false, 1, &titles).unwrap();
+        assert_eq!(one_edit.len(), 1);
+        assert_eq!(one_edit[&titles.get("felix").unwrap()], (titles.get("cat").unwrap(), 1));
+        let two_edits = index.search_fuzzy("cot", 1, false, 2, &titles).unwrap();
+        assert_eq!(two_edits[&titles.get("horse").unwrap()], (titles.get("cart").unwrap(), 2));
+        // The whole title is too long for an automaton, its words are still searched
+        let long_title = "the cot that was left in the attic for thirty years";
+        assert!(fst::automaton::Levenshtein::new(long_title, 2).is_err());
+        let long_search = index.search_fuzzy(long_title, 1, true, 2, &titles).unwrap();
+        assert_eq!(long_search[&titles.get("felix").unwrap()], (titles.get("cat").unwrap(), 1));
+        let in_memory = indexer::generate_inmemory_index(path, 1, false, titles.shared()).unwrap();
+        assert!(in_memory.search_fuzzy("cot", 1, false, 1, &titles).is_err());
+    }
+
+    #[test]
+    fn letter_patterns_match_whole_keys() {
+        let pattern = pattern::LetterPattern::parse("?A?E").unwrap();
+        assert!(pattern.matches("cake"));
+        assert!(!pattern.matches("cakes"));
+        assert!(!pattern.matches("c4ke"));
+        let pattern = pattern::LetterPattern::parse("moon*").unwrap();
+        assert!(pattern.matches("moon"));
+        assert!(pattern.matches("moon landing"));
+        let pattern = pattern::LetterPattern::parse("CV[st-v]#").unwrap();
+        assert!(pattern.matches("bat1"));
+        assert!(!pattern.matches("bar1"));
+        assert!(pattern::LetterPattern::parse("[ab").is_err());
+        // Set positions are counted in the trimmed pattern
+        assert_eq!(pattern::LetterPattern::parse("  A[!]").unwrap_err(), "pattern   A[!] has a bad set [!]");
+        assert!(pattern::LetterPattern::parse("a!").is_err());
+    }
+
+    #[test]
+    fn fst_index_searches_prefixes_and_patterns() {
+        let dir = TempDir::new("pattern_test");
+        let association = dir.join("association.txt");
+        std::fs::write(&association, "[\"moon\",[\"apollo\"]]\n[\"moonlight\",[\"sonata\"]]\n[\"cake\",[\"icing\"]]\n").unwrap();
+        let path = association.to_str().unwrap();
+        let titles = QueryTitles::new(Arc::new(TitleDict::new()));
+        let index = indexer::generate_fst_index(path, 1, false).unwrap();
+        let prefixed = index.search_keys(&pattern::KeyPattern::Prefix("moon".to_string()), 10, &titles).unwrap();
+        assert_eq!(prefixed.len(), 2);
+        assert_eq!(prefixed[&titles.get("sonata").unwrap()], titles.get("moonlight").unwrap());
+        let patterned = index.search_keys(&pattern::KeyPattern::Pattern(pattern::LetterPattern::parse("?A?E").unwrap()), 10, &titles).unwrap();
+        assert_eq!(patterned.keys().map(|id| titles.resolve(*id).to_string()).collect::<Vec<String>>(), vec!["icing"]);
+        // Too many keys
+        assert!(index.search_keys(&pattern::KeyPattern::Prefix(String::new()), 2, &titles).is_err());
+        // Bad patterns are caught before the query runs, prefixes are never bad
+        let stage = stages::KeyPatternStage::new("Pattern", Vec::new(), false);
+        assert!(stages::Stage::check_term(&stage, "[ab").is_err());
+        assert!(stages::Stage::check_term(&stage, "?A?E").is_ok());
+        assert!(stages::Stage::check_term(&stages::KeyPatternStage::new("Prefix", Vec::new(), true), "[ab").is_ok());
+    }
+
+    #[test]
+    fn transforms_keep_only_real_words() {
+        use transforms::{Transform, TransformKind};
+        let dir = TempDir::new("transforms_test");
+        let words = dir.join("words.txt");
+        std::fs::write(&words, "stressed,desserts,cat,chat,at,cot,hal,ibm,level\n").unwrap();
+        let titles = TitleDict::new();
+        let lexicons = vec![Arc::new(lexicon::generate_lexicon(words.to_str().unwrap(), &titles).unwrap())];
+        let index = transforms::SpellingIndex::new(&lexicons, &titles);
+        let found = |kind: TransformKind, text: &str| -> Vec<(String, Transform)> {
+            let mut found: Vec<(String, Transform)> = index.search(kind, text).into_iter().map(|(id, transform)| (titles.resolve(id).to_string(), transform)).collect();
+            found.sort_by(|a, b| a.0.cmp(&b.0));
+            found
+        };
+        assert_eq!(found(TransformKind::Reverse, "Stressed"), vec![("desserts".to_string(), Transform::Reverse)]);
+        // A palindrome is not a transform of itself
+        assert!(found(TransformKind::Reverse, "level").is_empty());
+        assert_eq!(found(TransformKind::DropLetter, "cat"), vec![("at".to_string(), Transform::Drop{at: 0, letter: 'c'})]);
+        assert_eq!(found(TransformKind::AddLetter, "cat"), vec![("chat".to_string(), Transform::Add{at: 1, letter: 'h'})]);
+        assert_eq!(found(TransformKind::ChangeLetter, "cat"), vec![("cot".to_string(), Transform::Change{at: 1, from: 'a', to: 'o'})]);
+        assert_eq!(found(TransformKind::Shift, "hal"), vec![("ibm".to_string(), Transform::Shift(1))]);
+        assert_eq!(Transform::Change{at: 1, from: 'a', to: 'o'}.to_string(), "changed a to o at 2");
+    }
+
+    #[test]
+    fn phrase_index_completes_both_sides() {
+        let dir = TempDir::new("phrases_test");
+        let association = dir.join("association.txt");
+        std::fs::write(&association, concat!(
+            "[\"brick and mortar\",[\"Mortar and Pestle\"]]\n",
+            "[\"drugstore cowboy\",[\"urban cowboy\"]]\n")).unwrap();
+        let titles = TitleDict::new();
+        let lexicons = vec![Arc::new(lexicon::generate_lexicon(association.to_str().unwrap(), &titles).unwrap())];
+        let index = phrases::PhraseIndex::new(&lexicons, &titles);
+        let names = |completions: Vec<(u32, u32)>| {
+            let mut names: Vec<(String, String)> = completions.iter().map(|(word, title)| (titles.resolve(*word).to_string(), titles.resolve(*title).to_string())).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(index.completions("Brick", &titles)), vec![("mortar".to_string(), "brick and mortar".to_string())]);
+        assert_eq!(names(index.completions("pestle", &titles)), vec![("mortar".to_string(), "mortar and pestle".to_string())]);
+        // Following the last word and preceding the first
+        assert_eq!(names(index.completions("cowboy", &titles)), vec![
+            ("drugstore".to_string(), "drugstore cowboy".to_string()),
+            ("urban".to_string(), "urban cowboy".to_string())]);
+        assert_eq!(names(index.completions("urban", &titles)), vec![("cowboy".to_string(), "urban cowboy".to_string())]);
+        assert!(index.completions("and", &titles).is_empty());
+    }
+
+    #[test]
+    fn specificity_rewards_rare_associations() {
+        let dir = TempDir::new("scoring_test");
+        let association = dir.join("association.txt");
+        std::fs::write(&association, concat!(
+            "[\"drugstore cowboy\",[\"united states\",\"gus van sant\"]]\n",
+            "[\"urban cowboy\",[\"united states\",\"list of drama films of the 1980s\"]]\n",
+            "[\"urban legend\",[\"united states\",\"folklore\"]]\n",
+            "[\"mall cop\",[\"united states\",\"list of drama films of the 1980s\"]]\n")).unwrap();
+        let path = association.to_str().unwrap();
+        let titles = Arc::new(TitleDict::new());
+        let in_memory = indexer::generate_inmemory_index(path, 1, false, &titles).unwrap();
+        let fst = indexer::generate_fst_index(path, 1, false).unwrap();
+        let binary_path = dir.join("association.txt.bin");
+        binary_index::write_binary_index(path, binary_path.to_str().unwrap(), 1, false).unwrap();
+        let binary = binary_index::open_binary_index(binary_path.to_str().unwrap()).unwrap();
+        let query_titles = QueryTitles::new(titles.clone());
+        let hub = titles.get("united states").unwrap();
+        let films = titles.get("list of drama films of the 1980s").unwrap();
+        // Every backend stores the same in-degrees
+        for index in [&in_memory as &dyn Searchable, &fst, &binary] {
+            assert_eq!(index.num_lines(), 4);
+            assert_eq!(index.in_degree(hub, &query_titles), 4);
+            assert_eq!(index.in_degree(films, &query_titles), 2);
+            assert_eq!(index.in_degree(query_titles.id("missing"), &query_titles), 0);
+        }
+        let specificity = scoring::Specificity::new(vec![Arc::new(in_memory)]);
+        let (hub_chance, films_chance) = (specificity.chance(hub, &query_titles).unwrap(), specificity.chance(films, &query_titles).unwrap());
+        assert!(scoring::surprisal(films_chance, 2) > scoring::surprisal(hub_chance, 2));
+        // Bigger association sets are weaker evidence
+        assert!(scoring::surprisal(films_chance, 2) > scoring::surprisal(films_chance, 3));
+        assert!(scoring::surprisal(0.001, 1) > 6.9 && scoring::surprisal(0.001, 1) < 6.91);
+        assert!(scoring::Specificity::default().chance(hub, &query_titles).is_none());
+    }
+
+    #[test]
+    fn result_cache() {
+        let cache = session::ResultCache::new(2, std::time::Duration::from_secs(60));
+        let (first, results) = cache.insert(vec![1, 2, 3]);
+        assert_eq!(*results, vec![1, 2, 3]);
+        let (second, _) = cache.insert(vec![4]);
+        assert_ne!(first, second);
+        assert_eq!(*cache.get(first).unwrap(), vec![1, 2, 3]);
+        // second is now least recently used, so it goes first
+        let (third, _) = cache.insert(vec![5]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(second).is_none());
+        assert!(cache.get(first).is_some() && cache.get(third).is_some());
+        assert!(cache.get(third + 1).is_none());
+        let expiring = session::ResultCache::new(2, std::time::Duration::from_millis(0));
+        let (cursor, _) = expiring.insert("results");
+        assert!(expiring.get(cursor).is_none());
+        assert!(expiring.is_empty());
+    }
+
+    #[test]
+    fn http_read_request() {
+        use std::io::BufReader;
+        let body = format!("{{\"terms\": [{}], \"stages\": [\"WikiAllStem\"]}}", vec!["\"urban\""; 200].join(","));
+        let raw = format!(concat!(
+            "\r\nPOST /query HTTP/1.1\r\nContent-Length: {}\r\nExpect: 100-continue\r\n\r\n{}",
+            "POST /query HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+            "5;ext\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n",
+            "GET / HTTP/1.0\r\n\r\n"), body.len(), body);
+        // A small buffer so the body takes many reads
+        let mut reader = BufReader::with_capacity(16, raw.as_bytes());
+        let mut written: Vec<u8> = Vec::new();
+        let timeout = std::time::Duration::from_secs(5);
+        let first = http::read_request(&mut reader, &mut written, timeout).unwrap().unwrap();
+        assert_eq!((first.method.as_str(), first.path.as_str()), ("POST", "/query"));
+        assert_eq!(first.body, body.as_bytes());
+        assert_eq!(first.header("content-length"), Some(body.len().to_string().as_str()));
+        assert!(first.keep_alive());
+        assert_eq!(written, b"HTTP/1.1 100 Continue\r\n\r\n");
+        let second = http::read_request(&mut reader, &mut written, timeout).unwrap().unwrap();
+        assert_eq!(second.body, b"hello world");
+        assert!(!second.keep_alive());
+        let third = http::read_request(&mut reader, &mut written, timeout).unwrap().unwrap();
+        assert!(third.body.is_empty() && !third.keep_alive());
+        assert!(http::read_request(&mut reader, &mut written, timeout).unwrap().is_none());
+
+        let status = |raw: &str| http::read_request(&mut raw.as_bytes(), &mut Vec::new(), timeout).unwrap_err().status();
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"), Some(400));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n"), Some(400));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"), Some(400));
+        assert_eq!(status("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"), Some(400));
+        assert_eq!(status("NOT HTTP\r\n\r\n"), Some(400));
+        assert_eq!(status(&format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", http::MAX_BODY_BYTES + 1)), Some(413));
+
+        let mut response: Vec<u8> = Vec::new();
+        http::Response::json(400, "{}".to_string()).write_to(&mut response, false).unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(),
+            "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}");
+    }
+
+    #[test]
+    fn http_read_request_deadline() {
+        use std::io::{self, BufRead, Read};
+        use std::time::{Duration, Instant};
+        // Like a socket with a read timeout, every stall is a timed out read
+        struct Stalling {
+            stalls: usize,
+            data: &'static [u8]
+        }
+        impl Read for Stalling {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let amount = self.fill_buf()?.len().min(buf.len());
+                buf[..amount].copy_from_slice(&self.data[..amount]);
+                self.consume(amount);
+                Ok(amount)
+            }
+        }
+        impl BufRead for Stalling {
+            fn fill_buf(&mut self) -> io::Result<&[u8]> {
+                if self.stalls > 0 {
+                    self.stalls -= 1;
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "stalled"));
+                }
+                Ok(self.data)
+            }
+            fn consume(&mut self, amount: usize) {
+                self.data = &self.data[amount..];
+            }
+        }
+        let raw: &'static [u8] = b"GET / HTTP/1.1\r\n\r\n";
+        // Timeouts before the deadline are waited out
+        let mut slow = Stalling{stalls: 3, data: raw};
+        let request = http::read_request(&mut slow, &mut Vec::new(), Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(request.path, "/");
+        // A client that never finishes is dropped at the deadline
+        let mut stuck = Stalling{stalls: usize::MAX, data: raw};
+        let start = Instant::now();
+        let error = http::read_request(&mut stuck, &mut Vec::new(), Duration::from_millis(50)).unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(error.status(), None);
+    }
+
+    #[test]
+    fn query_error_json() {
+        let error = QueryError::StageOrder{stage: "WikiArticleExact".to_string(), position: 1, first: true};
+        assert_eq!(error.status(), 400);
+        assert_eq!(error.to_json(), serde_json::json!({"error": {
+            "code": "stage_order",
+            "message": "WikiArticleExact (#1) can't be the first stage",
+            "stage": "WikiArticleExact"
+        }}));
+        let error = QueryError::OutOfBudget{stop: Stop::TooLarge(200001), stage: "Synonym".to_string(), position: 2, max_size: 100000};
+        assert_eq!((error.status(), error.code(), error.stage()), (422, "size_exceeded", Some("Synonym")));
+        assert_eq!(error.to_string(), "maximum working size 200001 exceeded max 100000 for stage: Synonym (#2)");
+        let error = QueryError::OutOfBudget{stop: Stop::TimedOut, stage: "Synonym".to_string(), position: 1, max_size: 100000};
+        assert_eq!(error.to_json()["error"]["code"], "timed_out");
+        let error = QueryError::InvalidField{field: "limit".to_string(), message: "must be at least 1".to_string()};
+        assert_eq!(error.to_json()["error"]["message"], "limit must be at least 1");
+        assert!(error.to_json()["error"]["stage"].is_null());
+        assert_eq!(QueryError::ExpiredCursor{cursor: 7}.status(), 410);
+        let error = QueryError::InvalidTerm{stage: "Pattern".to_string(), term: "[ab".to_string(), message: "pattern [ab has an unclosed [".to_string()};
+        assert_eq!((error.status(), error.code(), error.stage()), (400, "invalid_term", Some("Pattern")));
+    }
+
+    #[test]
+    fn http_write_event() {
+        let mut stream: Vec<u8> = Vec::new();
+        http::write_event_stream_head(&mut stream).unwrap();
+        http::write_event(&mut stream, "stage", "{\"stage\": \"WikiAllStem\"}").unwrap();
+        http::write_event(&mut stream, "done", "two\nlines").unwrap();
+        let text = String::from_utf8(stream).unwrap();
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n"));
+        assert!(!text.contains("Content-Length"));
+        assert!(text.ends_with("\r\n\r\nevent: stage\ndata: {\"stage\": \"WikiAllStem\"}\n\nevent: done\ndata: two\ndata: lines\n\n"));
+    }
+
+    #[test]
+    fn disconnect_watcher() {
+        use std::net::{TcpListener, TcpStream};
+        use std::time::Duration;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(7))).unwrap();
+        let budget = Budget::unlimited();
+        let token = budget.token();
+        let watcher = http::watch_disconnect(&server, move || token.cancel()).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(budget.check(0).is_ok());
+        drop(client);
+        for _ in 0..50 {
+            if budget.check(0).is_err() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(budget.stopped(), Some(Stop::Cancelled));
+        drop(watcher);
+        assert_eq!(server.read_timeout().unwrap(), Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn worker_pool_bounds_queue() {
+        use std::sync::mpsc::channel;
+        use std::sync::Mutex;
+        let (started, started_receiver) = channel::<u32>();
+        let (release, release_receiver) = channel::<()>();
+        let (started, release_receiver) = (Mutex::new(started), Mutex::new(release_receiver));
+        let pool = WorkerPool::new(1, 1, move |item: u32, _backlog: &super::pool::Backlog| {
+            started.lock().unwrap().send(item).unwrap();
+            release_receiver.lock().unwrap().recv().unwrap();
+        });
+        assert!(pool.try_submit(1).is_ok());
+        // The only worker is busy with 1, so 2 waits in the queue and 3 doesn't fit
+        assert_eq!(started_receiver.recv().unwrap(), 1);
+        assert!(pool.try_submit(2).is_ok());
+        assert_eq!(pool.queued(), 1);
+        assert_eq!(pool.try_submit(3), Err(3));
+        release.send(()).unwrap();
+        assert_eq!(started_receiver.recv().unwrap(), 2);
+        assert_eq!(pool.queued(), 0);
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn worker_pool_survives_panics() {
+        use std::sync::mpsc::channel;
+        use std::sync::Mutex;
+        let (done, done_receiver) = channel::<u32>();
+        let done = Mutex::new(done);
+        let pool = WorkerPool::new(1, 2, move |item: u32, _backlog: &super::pool::Backlog| {
+            if item == 1 {
+                panic!("handler failed on {}", item);
+            }
+            done.lock().unwrap().send(item).unwrap();
+        });
+        assert!(pool.try_submit(1).is_ok());
+        assert!(pool.try_submit(2).is_ok());
+        // The only worker panicked on 1 and still serves 2
+        assert_eq!(done_receiver.recv_timeout(std::time::Duration::from_secs(5)), Ok(2));
+    }
 }
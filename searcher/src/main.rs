@@ -4,302 +4,281 @@ extern crate searcher;
 extern crate fst;
 extern crate httparse;
 
-use std::thread;
 use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::time::{Instant};
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::time::{Duration, Instant};
 use std::sync::Arc;
 use std::net::TcpStream;
 use std::net::TcpListener;
+use std::path::Path;
 
 use serde_json::{json, Value};
 
-use searcher::{indexer, stemmer, synonym_index};
+use searcher::{binary_index, config, http, index_builder, indexer, lexicon, pronunciation_index, scoring, stages, stemmer, substring, synonym_index};
+use searcher::config::{Backend, IndexConfig, ServerConfig};
 
-use searcher::indexer::Searchable;
-
-#[derive(Debug)]
-enum QueryStage {
-    WikiAllStem,
-    WikiArticleStem,
-    WikiArticleExact,
-    Synonym,
-    Homophone
-}
+use searcher::budget::{Budget, Stop};
+use searcher::http::{DisconnectWatcher, Request, Response};
+use searcher::pool::{Backlog, WorkerPool};
+use searcher::indexer::IndexError;
+use searcher::query_error::{error_body, QueryError};
+use searcher::stages::{sum_subentries, AssociationDict, MatchDetail, SharedIndex, Stage, StageInput, StageRegistry, StageSources};
+use searcher::scoring::Specificity;
+use searcher::session::ResultCache;
+use searcher::titles::{ArticleId, QueryTitles, TitleDict};
 
 struct Query {
     query_terms: Vec<String>,
-    stages: Vec<QueryStage>,
-    max_size: usize,
+    stages: Vec<Arc<dyn Stage>>,
+    // Caps the working set size and running time, cancelled when the client leaves
+    budget: Budget,
     association_dicts: Vec<AssociationDict>,
     // Purely for scoring, TODO: make this structured in some kind of sane way
-    flavortext: Option<String>
+    flavortext: Option<String>,
+    specificity: Arc<Specificity>,
+    // Fewest terms an association has to be reached by
+    min_coverage: usize,
+    // Query terms and keys the indexes didn't intern get ids here, kept for as long as the query
+    titles: QueryTitles
 }
 
-#[derive(PartialEq, PartialOrd)]
-struct ScorePair {
-    score: f64,
-    association: String
+// A query after every stage ran (or as many as the budget allowed), kept in the result cache for paging
+struct ScoredQuery {
+    query: Query,
+    scored_pairs: Vec<ScorePair>,
+    stopped: Option<StoppedAt>
 }
 
-// This struct stores 1) original search term 2) the match
-// e.g. book -> book of job
-// this is to help us retrace our steps through association phases
-#[derive(Debug)]
-struct SearchMatch {
-    search_term: String,
-    search_match: String
+// The stage a query ran out of budget in, its results are from the stages before
+struct StoppedAt {
+    stop: Stop,
+    stage: String,
+    position: usize
 }
 
-type AssociationDict = HashMap<String, HashMap<String, SearchMatch>>;
-
-fn find_associations(search_set: &[String], norm_index: &Arc<impl Searchable>, table_index: &Arc<impl Searchable>) -> AssociationDict {
-    let mut association_dict: AssociationDict = HashMap::new();
-    for term in search_set {
-        let entry = association_dict.entry(term.to_string()).or_insert_with(HashMap::new);
-        let norm_results = norm_index.search(&term, 1, false);
-        let table_results = table_index.search(&term, 1, false);
-        for (search_child, search_match) in norm_results {
-            entry.insert(search_child.to_string(), SearchMatch{search_term: term.to_string(), search_match: search_match.to_string()});
-        }
-        for (search_child, search_match) in table_results {
-            entry.insert(search_child.to_string(), SearchMatch{search_term: term.to_string(), search_match: search_match.to_string()});
-        }
-    }
-    return association_dict;
+enum QueryRequest {
+    // Run the stages and score
+    Search(Box<Query>),
+    // Another page of an earlier search, by cursor
+    More(u64)
 }
 
-fn find_associations_norm(search_set: &[String], norm_index: &Arc<impl Searchable>) -> AssociationDict {
-    let mut association_dict: AssociationDict = HashMap::new();
-    for term in search_set {
-        let entry = association_dict.entry(term.to_string()).or_insert_with(HashMap::new);
-        let norm_results = norm_index.search(&term, 1, false);
-        for (search_child, search_match) in norm_results {
-            entry.insert(search_child.to_string(), SearchMatch{search_term: term.to_string(), search_match: search_match.to_string()});
-        }
-    }
-    return association_dict;
+// Which of the scored results to send back
+struct Page {
+    offset: usize,
+    limit: usize
 }
 
-fn find_synonym_associations(search_set: &[String], index: &Arc<synonym_index::SynonymIndex>) -> AssociationDict {
-    let mut association_dict: AssociationDict = HashMap::new();
-    for term in search_set {
-        let entry = association_dict.entry(term.to_string()).or_insert_with(HashMap::new);
-        let synonym_results = synonym_index::search_synonym_index(&term, index);
-        for (syn, _) in synonym_results {
-            // Need to map syn -> syn otherwise if we use 'term' we'll only get the last entry
-            entry.insert(syn.to_string(), SearchMatch{search_term: term.to_string(), search_match: term.to_string()});
-        }
-    }
-    return association_dict;
-}
-
-fn subfind_associations(associations: &AssociationDict, norm_index: &Arc<impl Searchable>) -> AssociationDict {
-    // map[item]-> map[article]->(title found in the article)
-    let mut association_dict: AssociationDict = HashMap::new();
-    // Iterate through items in search set
-    for (term, subassociations) in associations.iter() {
-        let entry = association_dict.entry(term.to_string()).or_insert_with(HashMap::new);
-        for (orig_search_child, _orig_search_match) in subassociations.iter() {
-
-            let norm_results = norm_index.search(orig_search_child, 0, true);
-            for (search_child, search_match) in norm_results {
-                entry.insert(search_child.to_string(), SearchMatch{search_term: orig_search_child.to_string(), search_match: search_match.to_string()});
-            }
-        }
-    }
-    return association_dict;
-}
-
-fn subfind_synonyms(associations: &AssociationDict, index: &Arc<synonym_index::SynonymIndex>) -> AssociationDict {
-    // map[item]-> map[article]->(title found in the article)
-    let mut association_dict: AssociationDict = HashMap::new();
-    // Iterate through items in search set
-    for (term, subassociations) in associations.iter() {
-        let entry = association_dict.entry(term.to_string()).or_insert_with(HashMap::new);
-        for (orig_search_child, _orig_search_match) in subassociations.iter() {
-
-            let synonym_results = synonym_index::search_synonym_index(&orig_search_child, index);
-            for (search_child, search_match) in synonym_results {
-                entry.insert(search_child.to_string(), SearchMatch{search_term: orig_search_child.to_string(), search_match: search_match.to_string()});
-            }
-        }
-    }
-    return association_dict;
-}
+const DEFAULT_MAX_SIZE: usize = 100000;
+const MAX_MAX_SIZE: usize = 10000000;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 1000;
+// Searches kept around for paging
+const RESULT_CACHE_SIZE: usize = 16;
+const RESULT_CACHE_TTL: Duration = Duration::from_secs(30 * 60);
+// Idle keep-alive connections are closed after this long, they hold a worker while they wait
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+// Once a request starts arriving all of it has to, so slow clients can't hold a worker either
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Socket read timeout, how often a waiting connection checks whether it should give its worker up
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
-fn subfind_associations_map(associations: &AssociationDict, norm_index: &Arc<impl Searchable>) -> AssociationDict {
-    // map[item]-> map[article]->title
-    let mut association_dict: AssociationDict = HashMap::new();
-    // Iterate through items in search set
-    for (term, subassociations) in associations.iter() {
-        let entry = association_dict.entry(term.to_string()).or_insert_with(HashMap::new);
-        for (orig_search_child, _orig_search_match) in subassociations.iter() {
-
-            // search returns <result entry, what matched that entry's key>
-            // since this is subfind we do 0 stemming and include the whole string
-            for (search_child, search_match) in norm_index.search(orig_search_child, 0, true) {
-                entry.insert(search_child.to_string(), SearchMatch{search_term: orig_search_child.to_string(), search_match: search_match.to_string()});
-            }
-        }
-    }
-    return association_dict;
+// Reported as each stage of a query finishes
+struct StageProgress {
+    stage: String,
+    // 1 for the first stage
+    position: usize,
+    // Entries in the stage's association dict
+    size: usize,
+    elapsed: Duration
 }
 
-fn sum_subentries(map_of_maps: &AssociationDict) -> usize {
-    let mut counter: usize = 0;
-    for (_, submap) in map_of_maps {
-        counter += submap.len();
-    }
-    return counter;
+#[derive(PartialEq, PartialOrd)]
+struct ScorePair {
+    score: f64,
+    association: ArticleId
 }
 
-fn construct_chains(query: &Query, scored_pairs: Vec<ScorePair>) -> Vec<HashMap<String, Vec<String>>> {
-    let mut all_results: Vec<HashMap<String, Vec<String>>> = Vec::new();
-    // TODO: allow this to be user specified?
-    let arbitrary_threshold = 50;
-    let mut num_processed = 0;
+/**
+ * One result per association: {"association", "score", "covered": [terms reaching it],
+ * "missing": [terms that don't], "chains": {covered term: [stage, search term, search match, result, ...]}}
+ */
+fn construct_chains(query: &Query, scored_pairs: &[ScorePair]) -> Vec<Value> {
+    let titles = &query.titles;
+    let mut all_results: Vec<Value> = Vec::new();
     let last_association_dict = query.association_dicts.last().unwrap();
     for score_pair in scored_pairs {
         let mut match_chains: HashMap<String, Vec<String>> = HashMap::new();
+        let mut covered: Vec<&str> = Vec::new();
+        let mut missing: Vec<&str> = Vec::new();
         for item in query.query_terms.iter() {
             // last search match -> last search term -> previous search match -> previous search term
             let mut chain: Vec<String> = Vec::new();
             let item_string = item.to_string();
-            let mut current_association = &score_pair.association;
-            match last_association_dict[item].get(&score_pair.association) {
-                Some(_v) => {
-                    // Start iterative construction
-                    let mut current_match;
-                    let num_stages = query.association_dicts.len();
-                    for stage_num in (0..num_stages).rev() {
-                        current_match = &query.association_dicts.get(stage_num).unwrap()[item][current_association];
-                        chain.push(current_association.to_string());
-                        chain.push(current_match.search_match.to_string());
-                        chain.push(current_match.search_term.to_string());
-                        chain.push(format!("{:?}", query.stages[stage_num]));
-                        current_association = &current_match.search_term;
-                    }
-                },
-                _ => {}
-            };
+            let mut current_association = score_pair.association;
+            if !last_association_dict[item].contains_key(&score_pair.association) {
+                missing.push(item);
+                continue;
+            }
+            covered.push(item);
+            // Start iterative construction
+            let mut current_match;
+            let num_stages = query.association_dicts.len();
+            for stage_num in (0..num_stages).rev() {
+                current_match = &query.association_dicts.get(stage_num).unwrap()[item][&current_association];
+                chain.push(titles.resolve(current_association).to_string());
+                let search_match = titles.resolve(current_match.search_match);
+                match current_match.detail {
+                    // Show where the search term is hidden in the match
+                    Some(MatchDetail::Position(position)) => {
+                        let length = substring::normalize(&titles.resolve(current_match.search_term)).chars().count();
+                        chain.push(substring::highlight(&search_match, position, length));
+                    },
+                    // Show which transform turned the search term into the result
+                    Some(MatchDetail::Transform(transform)) => chain.push(transform.to_string()),
+                    None => chain.push(search_match.to_string())
+                }
+                chain.push(titles.resolve(current_match.search_term).to_string());
+                chain.push(query.stages[stage_num].name().to_string());
+                current_association = current_match.search_term;
+            }
             match_chains.insert(item_string, chain.iter().rev().cloned().collect());
         }
-        num_processed += 1;
-        println!("{}: {}: {:?}", score_pair.score, &score_pair.association, match_chains);
-        all_results.push(match_chains);
-        if num_processed > arbitrary_threshold {
-            eprintln!("Terminating early at score: {}", score_pair.score);
-            break;
-        }
+        all_results.push(json!({
+            "association": &*titles.resolve(score_pair.association),
+            "score": score_pair.score,
+            "covered": covered,
+            "missing": missing,
+            "chains": match_chains
+        }));
     }
-    return all_results;
+    all_results
 }
 
-fn process_query(mut query_raw: Query,
-                 norm_index: Arc<impl Searchable>,
-                 table_index: Arc<impl Searchable>,
-                 syn_index: Arc<synonym_index::SynonymIndex>,
-                 homophone_index: Arc<synonym_index::SynonymIndex>) -> String {
+/**
+ * {"cursor": id for fetching more pages, "total": number of results, "offset", "limit", "results": [see construct_chains],
+ * "partial": null, or {"code", "message", "stage", "position"} for the stage the query stopped in}
+ */
+fn render_page(cursor: u64, scored: &ScoredQuery, page: &Page) -> String {
+    let total = scored.scored_pairs.len();
+    let start = page.offset.min(total);
+    let end = (start + page.limit).min(total);
+    let partial = scored.stopped.as_ref().map(|stopped| json!({
+        "code": stopped.stop.code(),
+        "message": stopped.stop.to_string(),
+        "stage": stopped.stage,
+        "position": stopped.position
+    }));
+    json!({
+        "cursor": cursor,
+        "total": total,
+        "offset": page.offset,
+        "limit": page.limit,
+        "results": construct_chains(&scored.query, &scored.scored_pairs[start..end]),
+        "partial": partial
+    }).to_string()
+}
+
+// Runs a new search or pages through a cached one
+fn respond_to_query(request: QueryRequest, page: &Page, cache: &ResultCache<ScoredQuery>,
+                    on_stage: &mut dyn FnMut(&Query, &StageProgress)) -> Result<String, QueryError> {
+    let (cursor, scored) = match request {
+        QueryRequest::Search(query) => cache.insert(process_query(*query, on_stage)?),
+        QueryRequest::More(cursor) => match cache.get(cursor) {
+            Some(scored) => (cursor, scored),
+            None => return Err(QueryError::ExpiredCursor{cursor})
+        }
+    };
+    Ok(render_page(cursor, &scored, page))
+}
+
+/**
+ * Runs every stage then scores what the last one found. on_stage hears about each stage as it
+ * finishes, with the query holding the association dicts so far. A query out of budget scores
+ * what the last finished stage found instead, or fails when no stage finished.
+ */
+fn process_query(mut query: Query, on_stage: &mut dyn FnMut(&Query, &StageProgress)) -> Result<ScoredQuery, QueryError> {
     let query_start = Instant::now();
-    let query = &mut query_raw;
-    for stage in query.stages.iter() {
-        let mut association_dict: AssociationDict = HashMap::new();
-        if query.association_dicts.len() > 0 {
-            let total_entries = sum_subentries(query.association_dicts.last().unwrap());
-            if  total_entries > query.max_size {
-                eprintln!("Aborting search as {} > maximum size {} for any association stage was exceeded.", total_entries, query.max_size);
-                return format!("{{\"error\": \"maximum working size {} exceeded max {} for stage: {:?} (#{})\"}}", total_entries, query.max_size, stage, query.association_dicts.len());
+    for stage in query.stages.clone() {
+        let position = query.association_dicts.len() + 1;
+        let stage_result = match query.association_dicts.last() {
+            None => stage.expand_initial(&query.query_terms[..], &query.titles, &query.budget),
+            Some(latest_associations) => {
+                let total_entries = sum_subentries(latest_associations);
+                eprintln!("{} subfind stage with {} associations", stage.name(), total_entries);
+                query.budget.check(total_entries).and_then(|_| stage.expand(latest_associations, &query.titles, &query.budget))
             }
-        }
-        println!("stage: {:?}", stage);
-        match stage {
-            QueryStage::WikiAllStem => {
-                eprintln!("WikiAll Stage");
-                if query.association_dicts.len() == 0 {
-                    association_dict.extend(find_associations(&query.query_terms[..], &norm_index, &table_index));
-                    query.association_dicts.push(association_dict);
-                } else {
-                    eprintln!("Cannot do subfind on all wiki indexes, use WikiArticleRefs insead");
-                }
-            },
-            QueryStage::WikiArticleStem => {
-                if query.association_dicts.len() == 0 {
-                    association_dict.extend(find_associations_norm(&query.query_terms[..], &norm_index));
-                    query.association_dicts.push(association_dict);
-                } else {
-                    let latest_associations = &query.association_dicts.last().unwrap();
-                    eprintln!("WikiArticleStem subfind stage with {} associations", sum_subentries(latest_associations));
-                    association_dict.extend(subfind_associations(latest_associations, &norm_index));
-                    query.association_dicts.push(association_dict);
-                }
-            },
-            QueryStage::WikiArticleExact => {
-                let latest_associations = &query.association_dicts.last().unwrap();
-                eprintln!("WikiArticleExact subfind stage with {} associations", sum_subentries(latest_associations));
-                association_dict.extend(subfind_associations_map(latest_associations, &norm_index));
-                query.association_dicts.push(association_dict);
-            },
-            QueryStage::Synonym => {
-                if query.association_dicts.len() == 0 {
-                    association_dict.extend(find_synonym_associations(&query.query_terms[..], &syn_index));
-                    query.association_dicts.push(association_dict);
-                } else {
-                    let latest_associations = &query.association_dicts.last().unwrap();
-                    eprintln!("Synonym subfind stage with {} associations", sum_subentries(latest_associations));
-                    association_dict.extend(subfind_synonyms(latest_associations, &syn_index));
-                    query.association_dicts.push(association_dict);
-                }
-            },
-            QueryStage::Homophone => {
-                if query.association_dicts.len() == 0 {
-                    association_dict.extend(find_synonym_associations(&query.query_terms[..], &homophone_index));
-                    println!("homophone associations: {:?}", &association_dict);
-                    query.association_dicts.push(association_dict);
-                } else {
-                    let latest_associations = &query.association_dicts.last().unwrap();
-                    eprintln!("Synonym subfind stage with {} associations", sum_subentries(latest_associations));
-                    association_dict.extend(subfind_synonyms(latest_associations, &homophone_index));
-                    query.association_dicts.push(association_dict);
+        };
+        let association_dict = match stage_result {
+            Ok(association_dict) => association_dict,
+            Err(message) => {
+                let stop = match query.budget.stopped() {
+                    Some(stop) => stop,
+                    None => {
+                        eprintln!("Stage {} failed: {}", stage.name(), message);
+                        return Err(QueryError::StageFailed{stage: stage.name().to_string(), message});
+                    }
+                };
+                eprintln!("Stopping in stage {} (#{}): {}", stage.name(), position, stop);
+                if query.association_dicts.is_empty() {
+                    return Err(QueryError::OutOfBudget{stop, stage: stage.name().to_string(), position, max_size: query.budget.max_size});
                 }
-            },
-        }
-        eprintln!("stage finished: {}s", query_start.elapsed().as_secs());
+                let scored_pairs = score_query(&query);
+                return Ok(ScoredQuery{query, scored_pairs, stopped: Some(StoppedAt{stop, stage: stage.name().to_string(), position})});
+            }
+        };
+        let progress = StageProgress{
+            stage: stage.name().to_string(),
+            position,
+            size: sum_subentries(&association_dict),
+            elapsed: query_start.elapsed()
+        };
+        query.association_dicts.push(association_dict);
+        eprintln!("stage {} finished: {}s", stage.name(), progress.elapsed.as_secs());
+        on_stage(&query, &progress);
     }
+    let scored_pairs = score_query(&query);
+    Ok(ScoredQuery{query, scored_pairs, stopped: None})
+}
+
+// Associations the last stage found, best first
+fn score_query(query: &Query) -> Vec<ScorePair> {
+    let titles = &query.titles;
     // Finally, we check if we got any good associations
-    let mut association_count_dict: HashMap<String, usize> = HashMap::new();
+    // association -> (number of terms reaching it, sum of their chain weights)
+    let mut association_count_dict: HashMap<ArticleId, (usize, f64)> = HashMap::new();
     let last_association_dict = query.association_dicts.last().unwrap();
+    // Terms reaching a lot of associations are weaker evidence for each one
+    let mut term_associations: HashMap<&str, usize> = HashMap::new();
     for item in query.query_terms.iter() {
-        match last_association_dict.get(item) {
-            Some(entry) => {
-                for (key, _) in entry {
-                    let key_string = key.to_string();
-                    association_count_dict.entry(key_string).and_modify(|e| {*e += 1}).or_insert(1);
-                }
+        if let Some(entry) = last_association_dict.get(item) {
+            term_associations.insert(item, entry.len());
+            for (key, search_match) in entry.iter() {
+                let counts = association_count_dict.entry(*key).or_insert((0, 0.0));
+                counts.0 += 1;
+                counts.1 += search_match.weight;
             }
-            None => {}
         }
     }
     // Stem the flavortext
     let mut flavortext_set: HashSet<String> = HashSet::new();
-    let use_flavortext_filter;
-    match &query.flavortext {
+    let use_flavortext_filter = match &query.flavortext {
         Some(flavortext) => {
             // max stem group 1 (word by word) and do not include the entire text (false)
-            for stem in stemmer::generate_stems(&flavortext, 1, false) {
+            for stem in stemmer::generate_stems(flavortext, 1, false) {
                 flavortext_set.insert(stem.to_string());
             }
-            use_flavortext_filter = true;
+            true
         },
-        None => {
-            use_flavortext_filter = false;
-        }
-    }
+        None => false
+    };
     // TODO: add scoring based on flavortext if it exists
     let mut scored_pairs: Vec<ScorePair> = Vec::new();
-    for (assoc, count) in association_count_dict {
+    for (assoc, (count, weight)) in association_count_dict {
         // Score each association
         // Our scoring approach is a bit qualitative:
         // - Imagine we get 100k 5/5 matches (synonym expansion) with no thematic filter,
@@ -315,21 +294,33 @@ fn process_query(mut query_raw: Query,
         // - Do both signals independently and use the one that provides more information? (higher
         // selectivity)
         // - For now, score is straight up (count) + ((# thematic)/(# words) in association)
+        // - Fuzzy steps (e.g. Phonetic near misses) weigh less than 1, so count is really the sum of weights
+        // - Hub articles linked from everything make count meaningless, so when the indexes know
+        // in-degrees each term adds the surprisal of reaching this association by chance (in nats,
+        // times its chain weight), rare associations shared by several terms win
 
-        // Debate aside, we can safely ignore 0 or 1 matches
-        if count <= 1 {
+        // Debate aside, we can safely ignore associations too few terms reach (by default 0 or 1)
+        if count < query.min_coverage {
             continue;
         }
 
-        let mut score: f64 = (count as f64) * 100.0;
+        let mut score: f64 = match query.specificity.chance(assoc, titles) {
+            Some(chance) => {
+                let mut evidence = 0.0;
+                for (item, associations) in &term_associations {
+                    if let Some(search_match) = last_association_dict[*item].get(&assoc) {
+                        evidence += search_match.weight * scoring::surprisal(chance, *associations);
+                    }
+                }
+                evidence
+            },
+            None => weight * 100.0
+        };
         if use_flavortext_filter {
             let mut assoc_stems: Vec<String> = Vec::new();
             let mut thematic_stems: f64 = 0.0;
-            for stem in stemmer::generate_stems(&assoc, 1, false) {
-                match flavortext_set.get(&stem) {
-                    Some(_) => {thematic_stems += 1.0},
-                    None => {}
-                }
+            for stem in stemmer::generate_stems(&titles.resolve(assoc), 1, false) {
+                if flavortext_set.contains(&stem) {thematic_stems += 1.0}
                 assoc_stems.push(stem);
             }
             score += thematic_stems;
@@ -340,162 +331,557 @@ fn process_query(mut query_raw: Query,
 //                score += thematic_stems / (total_stems as f64);
 //            }
         }
-        scored_pairs.push(ScorePair{score: score, association: assoc.to_string()});
+        scored_pairs.push(ScorePair{score, association: assoc});
     }
     // Need to sort f64s that don't implement Eq (damn you Rust), we no there are no NaNs
     scored_pairs.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
     println!("Total scored associations: {}", scored_pairs.len());
-    return json!(construct_chains(&query, scored_pairs)).to_string();
+    scored_pairs
+}
+
+/**
+ * min_coverage is either a number of terms (2) or a fraction of them (0.8 of 5 terms is 4),
+ * by default 2 (or 1 for a single term)
+ */
+fn parse_min_coverage(value: Option<&Value>, num_terms: usize) -> Result<usize, QueryError> {
+    let invalid = |message: String| QueryError::InvalidField{field: "min_coverage".to_string(), message};
+    let default = num_terms.clamp(1, 2);
+    let coverage = match value {
+        None | Some(Value::Null) => return Ok(default),
+        Some(value) => value.as_f64().ok_or_else(|| invalid(format!("must be a number, got {}", value)))?
+    };
+    let min_coverage = if coverage > 0.0 && coverage < 1.0 {
+        (coverage * num_terms as f64).ceil() as usize
+    } else if coverage >= 1.0 && coverage.fract() == 0.0 {
+        coverage as usize
+    } else {
+        return Err(invalid(format!("must be a fraction between 0 and 1 or a whole number of terms, got {}", coverage)));
+    };
+    if min_coverage > num_terms {
+        return Err(invalid(format!("of {} is more than the {} terms", min_coverage, num_terms)));
+    }
+    Ok(min_coverage.max(1))
+}
+
+// Non-negative whole number field, or default when missing
+fn parse_count(value: Option<&Value>, name: &str, default: usize) -> Result<usize, QueryError> {
+    match value {
+        None | Some(Value::Null) => Ok(default),
+        Some(value) => match value.as_u64() {
+            Some(count) => Ok(count as usize),
+            None => Err(QueryError::InvalidField{field: name.to_string(), message: format!("must be a non-negative whole number, got {}", value)})
+        }
+    }
+}
+
+// Which slice of the results to return, limit is capped at MAX_LIMIT
+fn parse_page(object: &serde_json::Map<String, Value>) -> Result<Page, QueryError> {
+    let offset = parse_count(object.get("offset"), "offset", 0)?;
+    let limit = parse_count(object.get("limit"), "limit", DEFAULT_LIMIT)?;
+    if limit == 0 {
+        return Err(QueryError::InvalidField{field: "limit".to_string(), message: "must be at least 1".to_string()});
+    }
+    Ok(Page{offset, limit: limit.min(MAX_LIMIT)})
 }
 
-fn parse_http_query(body: &mut [u8]) -> Query {
-    println!("body: {:?}", body);
-    let v: Value = simd_json::serde::from_slice(body).unwrap();
-    println!("{:?}", v);
-    let object = v.as_object().unwrap();
+/**
+ * Rejects stage names that aren't registered and stages in an order they can't run in,
+ * a cursor asks for another page of earlier results
+ */
+fn parse_http_query(body: &mut [u8], registry: &StageRegistry, titles: &Arc<TitleDict>) -> Result<(QueryRequest, Page), QueryError> {
+    let v: Value = match simd_json::serde::from_slice(body) {
+        Ok(v) => v,
+        Err(error) => return Err(QueryError::InvalidJson{message: error.to_string()})
+    };
+    let object = match v.as_object() {
+        Some(object) => object,
+        None => return Err(QueryError::InvalidJson{message: "expected an object".to_string()})
+    };
+    let page = parse_page(object)?;
+    if let Some(cursor_value) = object.get("cursor") {
+        return match cursor_value.as_u64() {
+            Some(cursor) => Ok((QueryRequest::More(cursor), page)),
+            None => Err(QueryError::InvalidField{field: "cursor".to_string(), message: format!("must be a number from an earlier response, got {}", cursor_value)})
+        };
+    }
     // Parse query stages array
-    let query_stages_array = object.get("stages").unwrap().as_array().unwrap();
+    let query_stages_array = match object.get("stages").and_then(Value::as_array) {
+        Some(stages) if !stages.is_empty() => stages,
+        _ => return Err(QueryError::InvalidField{field: "stages".to_string(), message: "must be a non-empty list of stage names".to_string()})
+    };
     // Parse terms
-    let query_terms_array = object.get("terms").unwrap().as_array().unwrap();
+    let query_terms_array = match object.get("terms").and_then(Value::as_array) {
+        Some(terms) if !terms.is_empty() => terms,
+        Some(_) => return Err(QueryError::EmptyTerms),
+        None => return Err(QueryError::InvalidField{field: "terms".to_string(), message: "must be a list of strings".to_string()})
+    };
     // Parse flavortext
     let flavortext_value = object.get("flavortext");
-    println!("query_stages: {:?}", query_stages_array);
-    println!("query_terms: {:?}", query_terms_array);
-    println!("flavortext: {:?}", flavortext_value);
 
     // Get query set, split by ","
     let mut query_terms: Vec<String> = Vec::new();
     for term_value in query_terms_array {
-        query_terms.push(term_value.as_str().unwrap().to_string());
+        match term_value.as_str() {
+            Some(term) if term.trim().is_empty() => return Err(QueryError::EmptyTerms),
+            Some(term) => query_terms.push(term.to_string()),
+            None => return Err(QueryError::InvalidField{field: "terms".to_string(), message: format!("must be strings, got {}", term_value)})
+        }
     }
-    let mut stages: Vec<QueryStage> = Vec::new();
+    let mut stages: Vec<Arc<dyn Stage>> = Vec::new();
     for stage_value in query_stages_array {
-        let stage_str = stage_value.as_str().unwrap();
-        match stage_str {
-            "WikiAllStem" => stages.push(QueryStage::WikiAllStem),
-            "WikiArticleStem" => stages.push(QueryStage::WikiArticleStem),
-            "WikiArticleExact" => stages.push(QueryStage::WikiArticleExact),
-            "Synonym" => stages.push(QueryStage::Synonym),
-            "Homophone" => stages.push(QueryStage::Homophone),
-            _ => {}
+        let stage_str = match stage_value.as_str() {
+            Some(stage_str) => stage_str,
+            None => return Err(QueryError::InvalidField{field: "stages".to_string(), message: format!("must be stage names, got {}", stage_value)})
+        };
+        let stage = match registry.get(stage_str) {
+            Some(stage) => stage,
+            None => return Err(QueryError::UnknownStage{stage: stage_str.to_string(), available: registry.names()})
+        };
+        let first = stages.is_empty();
+        if (first && !stage.can_start()) || (!first && !stage.can_follow()) {
+            return Err(QueryError::StageOrder{stage: stage_str.to_string(), position: stages.len() + 1, first});
         }
+        stages.push(stage);
+    }
+    // Only the first stage searches the terms themselves
+    for term in &query_terms {
+        stages[0].check_term(term).map_err(|message| QueryError::InvalidTerm{stage: stages[0].name().to_string(), term: term.to_string(), message})?;
     }
-    let max_size: usize = 100000;
+    let max_size = parse_count(object.get("max_size"), "max_size", DEFAULT_MAX_SIZE)?.min(MAX_MAX_SIZE);
+    let timeout_ms = parse_count(object.get("timeout_ms"), "timeout_ms", DEFAULT_TIMEOUT.as_millis() as usize)?;
+    if timeout_ms == 0 {
+        return Err(QueryError::InvalidField{field: "timeout_ms".to_string(), message: "must be at least 1".to_string()});
+    }
+    let budget = Budget::new(max_size, Some(Duration::from_millis(timeout_ms as u64).min(MAX_TIMEOUT)));
     let association_dicts: Vec<AssociationDict> = Vec::new();
     let mut flavortext: Option<String> = None;
-    match flavortext_value {
-        Some(flavortext_json_value) => {
-            flavortext = Some(flavortext_json_value.to_string());
+    if let Some(flavortext_json_value) = flavortext_value {
+        flavortext = Some(flavortext_json_value.to_string());
+    }
+    let min_coverage = parse_min_coverage(object.get("min_coverage"), query_terms.len())?;
+    let query = Query{query_terms, stages, budget, association_dicts, flavortext, specificity: registry.specificity(), min_coverage,
+        titles: QueryTitles::new(titles.clone())};
+    Ok((QueryRequest::Search(Box::new(query)), page))
+}
+
+// Static files the UI needs, by path
+fn static_file(path: &str, content_type: &'static str) -> Response {
+    match std::fs::read(path) {
+        Ok(contents) => Response::new(200, content_type, contents),
+        Err(error) => {
+            eprintln!("Couldn't read {}: {}", path, error);
+            Response::new(404, "text/plain", "not found")
+        }
+    }
+}
+
+// Cancels a search if the client hangs up before it finishes, for as long as the watcher lives
+fn watch_query(request: &QueryRequest, connection: &TcpStream) -> Option<DisconnectWatcher> {
+    let token = match request {
+        QueryRequest::Search(query) => query.budget.token(),
+        QueryRequest::More(_) => return None
+    };
+    match http::watch_disconnect(connection, move || token.cancel()) {
+        Ok(watcher) => Some(watcher),
+        Err(error) => {
+            eprintln!("Couldn't watch connection: {}", error);
+            None
+        }
+    }
+}
+
+fn route(mut request: Request, connection: &TcpStream, registry: &StageRegistry, titles: &Arc<TitleDict>, cache: &ResultCache<ScoredQuery>) -> Response {
+    eprintln!("{} {}", request.method, request.path);
+    match request.method.as_str() {
+        "GET" => match request.path.as_str() {
+            "/" => static_file("static/index.html", "text/html; charset=utf-8"),
+            "/js/app.js" => static_file("static/js/app.js", "application/javascript"),
+            "/css/main.css" => static_file("static/css/main.css", "text/css"),
+            _ => Response::new(404, "text/plain", "not found")
         },
-        None => {}
+        "POST" => {
+            let result = parse_http_query(&mut request.body, registry, titles).and_then(|(query_request, page)| {
+                let _watcher = watch_query(&query_request, connection);
+                respond_to_query(query_request, &page, cache, &mut |_, _| {})
+            });
+            match result {
+                Ok(body) => Response::json(200, body),
+                Err(error) => {
+                    eprintln!("Query failed: {}", error);
+                    Response::json(error.status(), error.to_json().to_string())
+                }
+            }
+        },
+        _ => Response::new(405, "text/plain", "method not allowed")
     }
-    return Query{query_terms, stages, max_size, association_dicts, flavortext};
 }
 
-fn handle_connection(mut stream: TcpStream,
-                     norm_index: Arc<impl Searchable>,
-                     table_index: Arc<impl Searchable>,
-                     syn_index: Arc<synonym_index::SynonymIndex>,
-                     homophone_index: Arc<synonym_index::SynonymIndex>) {
-    let mut buffer = [0; 256 * 1024];
-    stream.read(&mut buffer).unwrap();
-
-    let mut headers = [httparse::EMPTY_HEADER; 32];
-    let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(&buffer).unwrap();
-    if !res.is_partial() {
-        let response = "HTTP/1.1 200 OK\r\n\r\n";
-        println!("Method: {:?}", req.method.unwrap_or("missing"));
-        println!("Path: {:?}", req.path.unwrap_or("no path"));
-        match req.method {
-            Some("GET") => {
-                println!("GET REQUEST");
-                match req.path {
-                    Some("/") => {
-                        let mut file = File::open("static/index.html").unwrap();
-                        let mut buffer = String::new();
-                        file.read_to_string(&mut buffer).unwrap();
-                        stream.write(format!("{}{}", response, buffer).as_bytes()).unwrap();
-                        stream.flush().unwrap();
-                    },
-                    Some("/js/app.js") => {
-                        let mut file = File::open("static/js/app.js").unwrap();
-                        let mut buffer = String::new();
-                        file.read_to_string(&mut buffer).unwrap();
-                        stream.write(format!("{}{}", response, buffer).as_bytes()).unwrap();
-                        stream.flush().unwrap();
-                    },
-                    Some("/css/main.css") => {
-                        let mut file = File::open("static/css/main.css").unwrap();
-                        let mut buffer = String::new();
-                        file.read_to_string(&mut buffer).unwrap();
-                        stream.write(format!("{}{}", response, buffer).as_bytes()).unwrap();
-                        stream.flush().unwrap();
-                    },
-                    _ => {
-                        stream.write(response.as_bytes()).unwrap();
-                        stream.flush().unwrap();
+/**
+ * Server-Sent Events for POST /query/stream: "stage" as each stage finishes ({stage, position, size, elapsed}),
+ * "results" with the best associations so far after every stage but the last ({stage, position, total, results}),
+ * then "done" with the first page just like /query returns, or "error" with the usual error body.
+ * Queries that don't parse get a plain error response instead.
+ */
+fn stream_query(mut request: Request, writer: &mut TcpStream, registry: &StageRegistry, titles: &Arc<TitleDict>, cache: &ResultCache<ScoredQuery>) {
+    let (query_request, page) = match parse_http_query(&mut request.body, registry, titles) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            eprintln!("Query failed: {}", error);
+            let _ = Response::json(error.status(), error.to_json().to_string()).write_to(writer, false);
+            return;
+        }
+    };
+    if let Err(error) = http::write_event_stream_head(writer) {
+        eprintln!("Couldn't start event stream: {}", error);
+        return;
+    }
+    let (num_stages, token) = match &query_request {
+        QueryRequest::Search(query) => (query.stages.len(), Some(query.budget.token())),
+        QueryRequest::More(_) => (0, None)
+    };
+    let _watcher = watch_query(&query_request, writer);
+    let mut connected = true;
+    let result = {
+        let mut on_stage = |query: &Query, progress: &StageProgress| {
+            let mut events = vec![("stage", json!({
+                "stage": progress.stage,
+                "position": progress.position,
+                "size": progress.size,
+                "elapsed": progress.elapsed.as_secs_f64()
+            }))];
+            if connected && progress.position < num_stages {
+                let scored_pairs = score_query(query);
+                let best = &scored_pairs[..scored_pairs.len().min(page.limit)];
+                events.push(("results", json!({
+                    "stage": progress.stage,
+                    "position": progress.position,
+                    "total": scored_pairs.len(),
+                    "results": construct_chains(query, best)
+                })));
+            }
+            for (event, data) in events {
+                if connected && http::write_event(writer, event, &data.to_string()).is_err() {
+                    eprintln!("Client left during {}", progress.stage);
+                    connected = false;
+                    if let Some(token) = &token {
+                        token.cancel();
                     }
                 }
+            }
+        };
+        respond_to_query(query_request, &page, cache, &mut on_stage)
+    };
+    let (event, data) = match result {
+        Ok(body) => ("done", body),
+        Err(error) => {
+            eprintln!("Query failed: {}", error);
+            ("error", error.to_json().to_string())
+        }
+    };
+    if connected {
+        let _ = http::write_event(writer, event, &data);
+    }
+}
+
+/**
+ * Waits for the next request to start arriving. False when the client closed the connection, stayed
+ * idle for KEEP_ALIVE_TIMEOUT or, on a kept alive connection, other connections are waiting for a worker.
+ */
+fn wait_for_request(reader: &mut BufReader<TcpStream>, backlog: &Backlog, kept_alive: bool) -> bool {
+    let idle_since = Instant::now();
+    loop {
+        match reader.fill_buf() {
+            Ok(buffered) => return !buffered.is_empty(),
+            Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if idle_since.elapsed() >= KEEP_ALIVE_TIMEOUT || (kept_alive && backlog.waiting() > 0) {
+                    return false;
+                }
             },
-            Some("POST") => {
-                println!("POST REQUEST");
-                let start_body = res.unwrap();
-                let mut end_body = start_body;
-                for i in start_body..buffer.len() {
-                    if buffer[i] != 0 {
-                        end_body += 1;
-                    } else {
-                        break;
-                    }
+            Err(_) => return false
+        }
+    }
+}
+
+/**
+ * Serves requests on one connection until the client closes it, asks to, or goes idle.
+ * The connection is closed when other connections are waiting for a worker, after a response
+ * or while waiting for the next request.
+ */
+fn handle_connection(stream: TcpStream, registry: &StageRegistry, titles: &Arc<TitleDict>, cache: &ResultCache<ScoredQuery>, backlog: &Backlog) {
+    if let Err(error) = stream.set_read_timeout(Some(READ_POLL_INTERVAL)) {
+        eprintln!("Couldn't set connection timeout: {}", error);
+    }
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(error) => {
+            eprintln!("Couldn't use connection: {}", error);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    let mut kept_alive = false;
+    loop {
+        if !wait_for_request(&mut reader, backlog, kept_alive) {
+            break;
+        }
+        let request = match http::read_request(&mut reader, &mut writer, REQUEST_TIMEOUT) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(error) => {
+                if let Some(status) = error.status() {
+                    eprintln!("Rejecting request: {}", error);
+                    let _ = Response::json(status, error_body(error.code(), &error.to_string(), None).to_string()).write_to(&mut writer, false);
                 }
-                let body: &mut [u8] = &mut buffer[res.unwrap()..end_body];
-                let query = parse_http_query(body);
-                let res = process_query(query, norm_index, table_index, syn_index, homophone_index);
-                stream.write(format!("{}{}", response, res).as_bytes()).unwrap();
-                stream.flush().unwrap();
+                break;
+            }
+        };
+        if request.method == "POST" && request.path == "/query/stream" {
+            stream_query(request, &mut writer, registry, titles, cache);
+            break;
+        }
+        let keep_alive = request.keep_alive() && backlog.waiting() == 0;
+        let response = route(request, reader.get_ref(), registry, titles, cache);
+        if let Err(error) = response.write_to(&mut writer, keep_alive) {
+            eprintln!("Couldn't send response: {}", error);
+            break;
+        }
+        if !keep_alive {
+            break;
+        }
+        kept_alive = true;
+    }
+}
+
+fn build_index(args: &[String]) {
+    if args.len() < 3 {
+        eprintln!("Usage: ./searcher build-index [condensed.csv] [output dir (default .)] [max buffered pairs]");
+        return;
+    }
+    let condensed_path = &args[2];
+    let output_dir = args.get(3).map(|dir| dir.as_str()).unwrap_or(".");
+    let max_buffered_pairs = match args.get(4) {
+        Some(max_arg) => max_arg.parse().expect("max buffered pairs must be a number"),
+        None => index_builder::DEFAULT_MAX_BUFFERED_PAIRS
+    };
+    let norm_path = format!("{}/{}", output_dir, "big_norm_index.txt");
+    let table_path = format!("{}/{}", output_dir, "big_table_index.txt");
+    let now = Instant::now();
+    match index_builder::build_association_indexes(condensed_path, &norm_path, &table_path, max_buffered_pairs) {
+        Ok(stats) => {
+            println!("Processed {} records ({} skipped)", stats.records, stats.skipped);
+            println!("Wrote {} entries to {}", stats.norm_entries, norm_path);
+            println!("Wrote {} entries to {}", stats.table_entries, table_path);
+        },
+        Err(e) => {
+            eprintln!("Failed to build indexes from {}: {}", condensed_path, e);
+            std::process::exit(1);
+        }
+    }
+    // Same stemming the server uses for each file
+    write_binary(&norm_path, 1, true);
+    write_binary(&table_path, 1, false);
+    println!("finished building indexes in {}s", now.elapsed().as_secs());
+}
+
+fn write_binary(file_path: &str, max_group: usize, include_whole: bool) {
+    let binary_path = format!("{}.bin", file_path);
+    match binary_index::write_binary_index(file_path, &binary_path, max_group, include_whole) {
+        Ok(stats) => {
+            println!("Wrote {} entries, {} strings and {} stems to {}", stats.entries, stats.strings, stats.stems, binary_path);
+        },
+        Err(e) => {
+            eprintln!("Failed to write binary index for {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn build_binary(args: &[String]) {
+    if args.len() < 3 {
+        eprintln!("Usage: ./searcher build-binary [association file] [max group (default 1)] [include whole (default false)]");
+        return;
+    }
+    let max_group = match args.get(3) {
+        Some(max_arg) => max_arg.parse().expect("max group must be a number"),
+        None => 1
+    };
+    let include_whole = match args.get(4) {
+        Some(whole_arg) => whole_arg.parse().expect("include whole must be true or false"),
+        None => false
+    };
+    write_binary(&args[2], max_group, include_whole);
+}
+
+// fst and in-memory indexes prefer a binary association file (<file>.bin) over parsing the JSON lines file
+fn load_association_index(index_config: &IndexConfig, titles: &Arc<TitleDict>) -> Result<SharedIndex, IndexError> {
+    let file_path = &index_config.path;
+    let (max_group, include_whole) = (index_config.max_group, index_config.include_whole);
+    let binary_path = format!("{}.bin", file_path);
+    // Binary indexes can't walk their keys
+    let needs_fst = index_config.stages.iter().any(|stage| stages::needs_fst(stage));
+    if index_config.backend == Backend::Binary || (index_config.backend != Backend::Stemmed && !needs_fst && Path::new(&binary_path).exists()) {
+        let index = binary_index::open_binary_index(&binary_path).and_then(|index| {
+            match index.stamp_mismatch(file_path, max_group, include_whole) {
+                Some(reason) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("stale, {}", reason))),
+                None => Ok(index)
+            }
+        });
+        match index {
+            Ok(index) => {
+                println!("Using binary index {} ({} entries)", binary_path, index.num_entries());
+                return Ok(Arc::new(index));
             },
-            _ => {
-                let response = "HTTP/1.1 405 Method Not Allowed\r\n\r\n";
-                stream.write(response.as_bytes()).unwrap();
-                stream.flush().unwrap();
+            Err(e) if index_config.backend == Backend::Binary => {
+                return Err(IndexError::io(&binary_path, e));
+            },
+            Err(e) => {
+                eprintln!("Ignoring binary index {}: {}", binary_path, e);
             }
         }
-    } else {
-        let response = "HTTP/1.1 413 Payload Too Large\r\n\r\n";
-        stream.write(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+    }
+    match index_config.backend {
+        Backend::InMemory => Ok(Arc::new(indexer::generate_inmemory_index(file_path, max_group, include_whole, titles)?)),
+        Backend::Stemmed => Ok(Arc::new(indexer::generate_stemmed_index(file_path, max_group, include_whole, titles)?)),
+        _ => Ok(Arc::new(indexer::generate_fst_index(file_path, max_group, include_whole)?))
+    }
+}
+
+// What kind of stage an index can serve
+fn backend_input(backend: Backend) -> StageInput {
+    match backend {
+        Backend::Synonym => StageInput::WordLists,
+        Backend::Lexicon => StageInput::Lexicons,
+        Backend::Pronunciation => StageInput::Pronunciations,
+        _ => StageInput::Associations
+    }
+}
+
+// Loads each configured index once and registers the stages they serve
+fn load_stage_registry(config: &ServerConfig, titles: &Arc<TitleDict>) -> StageRegistry {
+    // Checked up front, loading can take minutes
+    for index_config in &config.indexes {
+        for name in &index_config.stages {
+            let input = stages::builtin_stage_input(name)
+                .unwrap_or_else(|| exit_on_config_error(format!("{}: unknown stage {}", index_config.name, name)));
+            if input != backend_input(index_config.backend) {
+                exit_on_config_error(format!("{}: a {} index can't serve stage {}", index_config.name, index_config.backend.name(), name));
+            }
+            if stages::needs_fst(name) && index_config.backend != Backend::Fst {
+                exit_on_config_error(format!("{}: only fst indexes can serve stage {}", index_config.name, name));
+            }
+        }
+    }
+    let mut sources = StageSources::default();
+    for index_config in &config.indexes {
+        println!("Loading {} ({}, {})", index_config.name, index_config.backend.name(), index_config.path);
+        match backend_input(index_config.backend) {
+            StageInput::WordLists => {
+                let index = Arc::new(exit_on_index_error(synonym_index::generate_synonym_index(&index_config.path, titles)));
+                for name in &index_config.stages {
+                    sources.word_lists.entry(name.to_string()).or_default().push(index.clone());
+                }
+            },
+            StageInput::Lexicons => {
+                let lexicon = Arc::new(exit_on_index_error(lexicon::generate_lexicon(&index_config.path, titles)));
+                for name in &index_config.stages {
+                    sources.lexicons.entry(name.to_string()).or_default().push(lexicon.clone());
+                }
+            },
+            StageInput::Pronunciations => {
+                let index = Arc::new(exit_on_index_error(pronunciation_index::generate_pronunciation_index(&index_config.path, titles)));
+                for name in &index_config.stages {
+                    sources.pronunciations.entry(name.to_string()).or_default().push(index.clone());
+                }
+            },
+            StageInput::Associations => {
+                let index = exit_on_index_error(load_association_index(index_config, titles));
+                for name in &index_config.stages {
+                    sources.associations.entry(name.to_string()).or_default().push(index.clone());
+                }
+            }
+        }
+    }
+    let mut registry = StageRegistry::new();
+    registry.register_builtins(sources, titles);
+    registry
+}
+
+fn exit_on_config_error(message: String) -> ! {
+    eprintln!("Bad config: {}", message);
+    std::process::exit(1);
+}
+
+// Refuses to serve with a broken index, the error names the file and line
+fn exit_on_index_error<T>(result: Result<T, IndexError>) -> T {
+    match result {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("Failed to load index {}", e);
+            std::process::exit(1);
+        }
     }
 }
 
 fn main() {
-    // first arg: filename, remaining args go into search set
+    // first arg: port or subcommand
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: ./searcher [port]");
+        eprintln!("Usage: ./searcher [port] [config.json]");
+        eprintln!("       ./searcher print-config");
+        eprintln!("       ./searcher build-index [condensed.csv] [output dir]");
+        eprintln!("       ./searcher build-binary [association file]");
         return;
     }
-    let port = &args[1];
-    let listener = TcpListener::bind(format!("{}:{}", "127.0.0.1", port)).unwrap();
-    let table_index_filename = "big_table_index.txt";
-    let norm_index_filename = "big_norm_index.txt";
-    let synonym_index_filename = "moby_words.txt";
-    let homophone_index_filename = "homophone_list.txt";
+    if args[1] == "build-index" {
+        build_index(&args);
+        return;
+    }
+    if args[1] == "build-binary" {
+        build_binary(&args);
+        return;
+    }
+    if args[1] == "print-config" {
+        // Starting point for a config file
+        println!("{}", serde_json::to_string_pretty(&ServerConfig::default().to_json()).unwrap());
+        return;
+    }
+    let port: u16 = match args[1].parse() {
+        Ok(port) => port,
+        Err(_) => {
+            eprintln!("Usage: ./searcher [port] [config.json]");
+            return;
+        }
+    };
+    let config = match args.get(2) {
+        Some(config_path) => config::load_config(config_path).unwrap_or_else(|e| exit_on_config_error(e.to_string())),
+        None => ServerConfig::default()
+    };
+    let listener = TcpListener::bind((config.bind_address.as_str(), port))
+        .unwrap_or_else(|e| exit_on_config_error(format!("can't listen on {} port {}: {}", config.bind_address, port, e)));
     let now = Instant::now();
-    let syn_index = Arc::new(synonym_index::generate_synonym_index(synonym_index_filename));
-    let homophone_index = Arc::new(synonym_index::generate_synonym_index(homophone_index_filename));
-    let table_index = Arc::new(indexer::generate_fst_index(table_index_filename, 1, false).unwrap());
-    let norm_index = Arc::new(indexer::generate_inmemory_index(norm_index_filename, 1, true));
-    println!("finished indexing in {}s", now.elapsed().as_secs());
+    // Shared by every index so ids are comparable across stages
+    let titles = Arc::new(TitleDict::new());
+    let registry = Arc::new(load_stage_registry(&config, &titles));
+    println!("finished indexing in {}s ({} titles)", now.elapsed().as_secs(), titles.len());
+    let cache = ResultCache::new(RESULT_CACHE_SIZE, RESULT_CACHE_TTL);
+    let pool = WorkerPool::new(config.workers, config.queue_size, move |stream: TcpStream, backlog: &Backlog| {
+        handle_connection(stream, &registry, &titles, &cache, backlog);
+    });
+    println!("listening on {} port {} with {} workers (queue of {})", config.bind_address, port, config.workers, config.queue_size);
     for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let thread_table_index = table_index.clone();
-        let thread_norm_index = norm_index.clone();
-        let thread_syn_index = syn_index.clone();
-        let thread_homophone_index = homophone_index.clone();
-        thread::spawn(|| {
-            handle_connection(stream, thread_norm_index, thread_table_index, thread_syn_index, thread_homophone_index);
-        });
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                eprintln!("Couldn't accept connection: {}", error);
+                continue;
+            }
+        };
+        if let Err(mut stream) = pool.try_submit(stream) {
+            eprintln!("Turning a connection away, {} already waiting", pool.queued());
+            let body = error_body("server_busy", "too many queries running, try again later", None).to_string();
+            let _ = Response::json(503, body).write_to(&mut stream, false);
+            continue;
+        }
         println!("Connection established!");
     }
 }
@@ -0,0 +1,125 @@
+extern crate serde_json;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde_json::{json, Value};
+
+// Bump whenever the layout of the fst/postings side files changes
+pub const BUILDER_VERSION: u64 = 3;
+
+/**
+ * Written next to the FST side files once they are completely built, so a missing or
+ * mismatched manifest means the side files can't be trusted.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexManifest {
+    pub source_size: u64,
+    pub source_mtime: u64,
+    pub content_hash: u64,
+    pub builder_version: u64,
+    pub max_group: usize,
+    pub include_whole: bool
+}
+
+// FNV-1a, stable across runs and platforms unlike the std hasher
+pub struct ContentHasher {
+    state: u64
+}
+
+impl Default for ContentHasher {
+    fn default() -> ContentHasher {
+        ContentHasher{state: 0xcbf29ce484222325}
+    }
+}
+
+impl ContentHasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.state
+    }
+}
+
+// Size and modification time (seconds since epoch) of the source file
+pub fn source_stamp<P: AsRef<Path>>(path: P) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    Ok((metadata.len(), mtime))
+}
+
+impl IndexManifest {
+    /**
+     * Compares everything that can be checked without reading the source file,
+     * returns a description of the first mismatch.
+     */
+    pub fn stamp_mismatch(&self, expected: &IndexManifest) -> Option<String> {
+        if self.builder_version != expected.builder_version {
+            return Some(format!("builder version {} != {}", self.builder_version, expected.builder_version));
+        }
+        if self.max_group != expected.max_group {
+            return Some(format!("max_group {} != {}", self.max_group, expected.max_group));
+        }
+        if self.include_whole != expected.include_whole {
+            return Some(format!("include_whole {} != {}", self.include_whole, expected.include_whole));
+        }
+        if self.source_size != expected.source_size {
+            return Some(format!("source size {} != {}", self.source_size, expected.source_size));
+        }
+        if self.source_mtime != expected.source_mtime {
+            return Some(format!("source mtime {} != {}", self.source_mtime, expected.source_mtime));
+        }
+        None
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "source_size": self.source_size,
+            "source_mtime": self.source_mtime,
+            // hex so it survives JSON readers that only do f64
+            "content_hash": format!("{:016x}", self.content_hash),
+            "builder_version": self.builder_version,
+            "max_group": self.max_group,
+            "include_whole": self.include_whole
+        })
+    }
+
+    pub fn from_json(v: &Value) -> Option<IndexManifest> {
+        Some(IndexManifest{
+            source_size: v.get("source_size")?.as_u64()?,
+            source_mtime: v.get("source_mtime")?.as_u64()?,
+            content_hash: u64::from_str_radix(v.get("content_hash")?.as_str()?, 16).ok()?,
+            builder_version: v.get("builder_version")?.as_u64()?,
+            max_group: v.get("max_group")?.as_u64()? as usize,
+            include_whole: v.get("include_whole")?.as_bool()?
+        })
+    }
+}
+
+// Ok(None) if there is no manifest or it can't be parsed
+pub fn read_manifest(path: &str) -> io::Result<Option<IndexManifest>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            Ok(serde_json::from_str::<Value>(&contents).ok().and_then(|v| IndexManifest::from_json(&v)))
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+// Written to a temporary file and renamed so a crash never leaves a partial manifest
+pub fn write_manifest(path: &str, manifest: &IndexManifest) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, manifest.to_json().to_string())?;
+    fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,191 @@
+use fst::Automaton;
+
+// One bit per pattern position, so patterns are capped at this many tokens
+const MAX_TOKENS: usize = 63;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Class {
+    Literal(u8),
+    Any,
+    Letter,
+    Consonant,
+    Vowel,
+    Digit,
+    Set(Vec<u8>)
+}
+
+impl Class {
+    // byte is an ASCII char, or the lead byte of a longer UTF-8 char
+    fn matches(&self, byte: u8) -> bool {
+        if !byte.is_ascii() {
+            // Accented letters and the like
+            return matches!(self, Class::Any | Class::Letter);
+        }
+        match self {
+            Class::Literal(literal) => byte == *literal,
+            Class::Any => true,
+            Class::Letter => byte.is_ascii_alphabetic(),
+            Class::Consonant => byte.is_ascii_alphabetic() && !b"aeiou".contains(&byte),
+            Class::Vowel => b"aeiou".contains(&byte),
+            Class::Digit => byte.is_ascii_digit(),
+            Class::Set(set) => set.contains(&byte)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    One(Class),
+    // Any run of characters, including none
+    Run
+}
+
+/**
+ * Whole key pattern in the style of crossword and Nutrimatic searches:
+ * - lower case letters, digits and spaces match themselves
+ * - ? or . is any one character, * is any run of characters
+ * - A is any letter, C a consonant, V a vowel, # a digit
+ * - [abc] or [a-f] is any one of a set of letters
+ *
+ * Other upper case letters match themselves, so ?A?E is "4 characters, a letter second, ending in e".
+ * Write letters lower case to match them literally: cave* finds keys starting with cave, CAVE* is
+ * a consonant, any letter, a vowel and an e.
+ */
+#[derive(Debug, Clone)]
+pub struct LetterPattern {
+    tokens: Vec<Token>
+}
+
+impl LetterPattern {
+    pub fn parse(pattern: &str) -> Result<LetterPattern, String> {
+        if !pattern.is_ascii() {
+            return Err(format!("pattern {} can only use ASCII characters", pattern));
+        }
+        let trimmed = pattern.trim();
+        let bytes = trimmed.as_bytes();
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let token = match bytes[i] {
+                b'?' | b'.' => Token::One(Class::Any),
+                b'*' => Token::Run,
+                b'A' => Token::One(Class::Letter),
+                b'C' => Token::One(Class::Consonant),
+                b'V' => Token::One(Class::Vowel),
+                b'#' => Token::One(Class::Digit),
+                b'[' => {
+                    let end = bytes[i..].iter().position(|b| *b == b']')
+                        .ok_or_else(|| format!("pattern {} has an unclosed [", pattern))? + i;
+                    let set = parse_set(&bytes[i + 1..end])
+                        .ok_or_else(|| format!("pattern {} has a bad set {}", pattern, &trimmed[i..=end]))?;
+                    i = end;
+                    Token::One(Class::Set(set))
+                },
+                byte if byte.is_ascii_alphanumeric() || byte == b' ' => Token::One(Class::Literal(byte.to_ascii_lowercase())),
+                byte => return Err(format!("pattern {} can't use {}", pattern, byte as char))
+            };
+            // ** is the same as *
+            if !(token == Token::Run && tokens.last() == Some(&Token::Run)) {
+                tokens.push(token);
+            }
+            i += 1;
+        }
+        if tokens.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        if tokens.len() > MAX_TOKENS {
+            return Err(format!("pattern {} is longer than {} characters", pattern, MAX_TOKENS));
+        }
+        Ok(LetterPattern{tokens})
+    }
+
+    // Positions reachable without reading anything more, * can match nothing
+    fn close(&self, mut positions: u64) -> u64 {
+        for (i, token) in self.tokens.iter().enumerate() {
+            if *token == Token::Run && positions & (1 << i) != 0 {
+                positions |= 1 << (i + 1);
+            }
+        }
+        positions
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let mut state = self.start();
+        for byte in text.bytes() {
+            state = self.accept(&state, byte);
+        }
+        self.is_match(&state)
+    }
+}
+
+// Letters and a-z style ranges
+fn parse_set(spec: &[u8]) -> Option<Vec<u8>> {
+    let mut set: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < spec.len() {
+        let first = spec[i].to_ascii_lowercase();
+        if !first.is_ascii_alphanumeric() {
+            return None;
+        }
+        if i + 2 < spec.len() && spec[i + 1] == b'-' {
+            let last = spec[i + 2].to_ascii_lowercase();
+            if last < first {
+                return None;
+            }
+            set.extend(first..=last);
+            i += 3;
+        } else {
+            set.push(first);
+            i += 1;
+        }
+    }
+    if set.is_empty() { None } else { Some(set) }
+}
+
+// Bit i is set when the first i tokens have matched
+impl Automaton for LetterPattern {
+    type State = u64;
+
+    fn start(&self) -> u64 {
+        self.close(1)
+    }
+
+    fn is_match(&self, state: &u64) -> bool {
+        state & (1 << self.tokens.len()) != 0
+    }
+
+    fn can_match(&self, state: &u64) -> bool {
+        *state != 0
+    }
+
+    fn accept(&self, state: &u64, byte: u8) -> u64 {
+        // The rest of a multi-byte char, the lead byte already moved us along
+        if byte & 0xC0 == 0x80 {
+            return *state;
+        }
+        let mut next: u64 = 0;
+        for (i, token) in self.tokens.iter().enumerate() {
+            if state & (1 << i) == 0 {
+                continue;
+            }
+            match token {
+                Token::Run => next |= 1 << i,
+                Token::One(class) => {
+                    if class.matches(byte) {
+                        next |= 1 << (i + 1);
+                    }
+                }
+            }
+        }
+        self.close(next)
+    }
+}
+
+/**
+ * Which index keys a Prefix or Pattern search wants
+ */
+#[derive(Debug, Clone)]
+pub enum KeyPattern {
+    Prefix(String),
+    Pattern(LetterPattern)
+}
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use super::lexicon::Lexicon;
+use super::titles::{ArticleId, TitleDict};
+
+// Every letter metaphone can output, '0' is "th"
+const CODE_LETTERS: &str = "0ABEFHIJKLMNOPRSTUWXY";
+
+// Weight of a match whose code is one edit away
+pub const NEAR_WEIGHT: f64 = 0.5;
+
+fn is_vowel(c: Option<&char>) -> bool {
+    matches!(c, Some('A') | Some('E') | Some('I') | Some('O') | Some('U'))
+}
+
+/**
+ * Original Metaphone (Lawrence Philips, 1990) of a single word, e.g. "knight" and "night" are both "NT"
+ */
+pub fn metaphone(word: &str) -> String {
+    let mut letters: Vec<char> = word.to_uppercase().chars().filter(|c| c.is_ascii_alphabetic()).collect();
+    if letters.is_empty() {
+        return String::new();
+    }
+    // Initial letter exceptions
+    match (letters.first(), letters.get(1)) {
+        (Some('A'), Some('E')) | (Some('G'), Some('N')) | (Some('K'), Some('N')) | (Some('P'), Some('N')) | (Some('W'), Some('R')) => {
+            letters.remove(0);
+        },
+        (Some('X'), _) => letters[0] = 'S',
+        (Some('W'), Some('H')) => {
+            letters.remove(1);
+        },
+        _ => {}
+    }
+    let at = |i: usize| letters.get(i);
+    let mut code = String::new();
+    for i in 0..letters.len() {
+        let c = letters[i];
+        // Doubled letters are encoded once, except C
+        if i > 0 && c != 'C' && letters[i - 1] == c {
+            continue;
+        }
+        let prev = if i > 0 { at(i - 1) } else { None };
+        let next = at(i + 1);
+        let after_next = at(i + 2);
+        match c {
+            'A' | 'E' | 'I' | 'O' | 'U' => {
+                if i == 0 {
+                    code.push(c);
+                }
+            },
+            'B' => {
+                // Silent in -MB
+                if !(prev == Some(&'M') && next.is_none()) {
+                    code.push('B');
+                }
+            },
+            'C' => {
+                if next == Some(&'I') && after_next == Some(&'A') {
+                    code.push('X');
+                } else if next == Some(&'H') {
+                    code.push(if prev == Some(&'S') { 'K' } else { 'X' });
+                } else if matches!(next, Some('I') | Some('E') | Some('Y')) {
+                    if prev != Some(&'S') {
+                        code.push('S');
+                    }
+                } else {
+                    code.push('K');
+                }
+            },
+            'D' => {
+                if next == Some(&'G') && matches!(after_next, Some('E') | Some('I') | Some('Y')) {
+                    code.push('J');
+                } else {
+                    code.push('T');
+                }
+            },
+            'G' => {
+                if next == Some(&'H') && !(after_next.is_none() || is_vowel(after_next)) {
+                    // Silent in -GHT, -GH- before a consonant
+                } else if next == Some(&'N') && (after_next.is_none() || (after_next == Some(&'E') && at(i + 3) == Some(&'D') && at(i + 4).is_none())) {
+                    // Silent in -GN and -GNED
+                } else if matches!(next, Some('I') | Some('E') | Some('Y')) && prev != Some(&'G') {
+                    code.push('J');
+                } else {
+                    code.push('K');
+                }
+            },
+            'H' => {
+                let silent_after = matches!(prev, Some('C') | Some('S') | Some('P') | Some('T') | Some('G'));
+                if !silent_after && (!is_vowel(prev) || is_vowel(next)) {
+                    code.push('H');
+                }
+            },
+            'K' => {
+                if prev != Some(&'C') {
+                    code.push('K');
+                }
+            },
+            'P' => code.push(if next == Some(&'H') { 'F' } else { 'P' }),
+            'Q' => code.push('K'),
+            'S' => {
+                if next == Some(&'H') || (next == Some(&'I') && matches!(after_next, Some('O') | Some('A'))) {
+                    code.push('X');
+                } else {
+                    code.push('S');
+                }
+            },
+            'T' => {
+                if next == Some(&'I') && matches!(after_next, Some('O') | Some('A')) {
+                    code.push('X');
+                } else if next == Some(&'H') {
+                    code.push('0');
+                } else if !(next == Some(&'C') && after_next == Some(&'H')) {
+                    code.push('T');
+                }
+            },
+            'V' => code.push('F'),
+            'W' | 'Y' => {
+                if is_vowel(next) {
+                    code.push(c);
+                }
+            },
+            'X' => code.push_str("KS"),
+            'Z' => code.push('S'),
+            // F J L M N R
+            _ => code.push(c)
+        }
+    }
+    code
+}
+
+/**
+ * Metaphone of each word run together, so "ice cream" and "I scream" share a code
+ */
+pub fn phonetic_code(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric()).map(metaphone).collect()
+}
+
+// Every code one insertion, deletion or substitution away
+fn near_codes(code: &str) -> HashSet<String> {
+    let letters: Vec<char> = code.chars().collect();
+    let mut near: HashSet<String> = HashSet::new();
+    for i in 0..letters.len() {
+        let mut deleted = letters.clone();
+        deleted.remove(i);
+        near.insert(deleted.into_iter().collect());
+        for replacement in CODE_LETTERS.chars() {
+            let mut substituted = letters.clone();
+            substituted[i] = replacement;
+            near.insert(substituted.into_iter().collect());
+        }
+    }
+    for i in 0..=letters.len() {
+        for insertion in CODE_LETTERS.chars() {
+            let mut inserted = letters.clone();
+            inserted.insert(i, insertion);
+            near.insert(inserted.into_iter().collect());
+        }
+    }
+    near.remove(code);
+    near.remove("");
+    near
+}
+
+/**
+ * Phonetic code -> every word or title that sounds like it
+ */
+pub struct PhoneticIndex {
+    codes: HashMap<String, Vec<ArticleId>>
+}
+
+impl PhoneticIndex {
+    pub fn new(lexicons: &[Arc<Lexicon>], titles: &TitleDict) -> PhoneticIndex {
+        let mut codes: HashMap<String, Vec<ArticleId>> = HashMap::new();
+        let mut seen: HashSet<ArticleId> = HashSet::new();
+        for lexicon in lexicons {
+            for word in lexicon.words() {
+                if !seen.insert(*word) {
+                    continue;
+                }
+                let code = phonetic_code(&titles.resolve(*word));
+                if !code.is_empty() {
+                    codes.entry(code).or_default().push(*word);
+                }
+            }
+        }
+        PhoneticIndex{codes}
+    }
+
+    /**
+     * Words sounding like text, weighted 1.0 for the same code and NEAR_WEIGHT for a code one edit away
+     */
+    pub fn search(&self, text: &str) -> Vec<(ArticleId, f64)> {
+        let code = phonetic_code(text);
+        let mut results: Vec<(ArticleId, f64)> = Vec::new();
+        if code.is_empty() {
+            return results;
+        }
+        if let Some(words) = self.codes.get(&code) {
+            results.extend(words.iter().map(|word| (*word, 1.0)));
+        }
+        for near_code in near_codes(&code) {
+            if let Some(words) = self.codes.get(&near_code) {
+                results.extend(words.iter().map(|word| (*word, NEAR_WEIGHT)));
+            }
+        }
+        results
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+}
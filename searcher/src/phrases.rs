@@ -0,0 +1,87 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use super::lexicon::Lexicon;
+use super::stemmer;
+use super::titles::{ArticleId, TitleDict};
+
+// Small words skipped between the two halves, so "brick and mortar" pairs brick with mortar
+const CONNECTORS: [&str; 6] = ["and", "or", "of", "the", "a", "n"];
+
+/**
+ * Which words come right before and after each word in titles, e.g. "drugstore cowboy" makes
+ * cowboy follow drugstore. Built from the same word groups generate_stems makes.
+ */
+#[derive(Default)]
+pub struct PhraseIndex {
+    // word -> (next word, a title with the two)
+    following: HashMap<ArticleId, Vec<(ArticleId, ArticleId)>>,
+    // word -> (previous word, a title with the two)
+    preceding: HashMap<ArticleId, Vec<(ArticleId, ArticleId)>>
+}
+
+// Words of text the way generate_stems sees them, in order
+fn words(text: &str) -> Vec<String> {
+    let mut whole = stemmer::generate_stems(text, 0, true);
+    match whole.pop() {
+        Some(joined) => joined.split(' ').filter(|word| !word.is_empty()).map(|word| word.to_string()).collect(),
+        None => Vec::new()
+    }
+}
+
+impl PhraseIndex {
+    pub fn new(lexicons: &[Arc<Lexicon>], titles: &TitleDict) -> PhraseIndex {
+        let mut index = PhraseIndex::default();
+        let mut seen_titles: HashSet<ArticleId> = HashSet::new();
+        let mut seen_pairs: HashSet<(ArticleId, ArticleId)> = HashSet::new();
+        for lexicon in lexicons {
+            for title in lexicon.words() {
+                if !seen_titles.insert(*title) {
+                    continue;
+                }
+                for stem in stemmer::generate_stems(&titles.resolve(*title), 3, false) {
+                    let parts: Vec<&str> = stem.split(' ').collect();
+                    let (first, second) = match parts[..] {
+                        [first, second] => (first, second),
+                        [first, connector, second] if CONNECTORS.contains(&connector) => (first, second),
+                        _ => continue
+                    };
+                    if CONNECTORS.contains(&first) || CONNECTORS.contains(&second) {
+                        continue;
+                    }
+                    let (first, second) = (titles.intern(first), titles.intern(second));
+                    if seen_pairs.insert((first, second)) {
+                        index.following.entry(first).or_default().push((second, *title));
+                        index.preceding.entry(second).or_default().push((first, *title));
+                    }
+                }
+            }
+        }
+        index
+    }
+
+    /**
+     * Words completing text into a two word phrase from some title: words following its last word
+     * and words preceding its first word, each with a title using the phrase
+     */
+    pub fn completions(&self, text: &str, titles: &TitleDict) -> Vec<(ArticleId, ArticleId)> {
+        let words = words(text);
+        let mut results: Vec<(ArticleId, ArticleId)> = Vec::new();
+        if let Some(last) = words.last().and_then(|word| titles.get(word)) {
+            results.extend(self.following.get(&last).into_iter().flatten());
+        }
+        if let Some(first) = words.first().and_then(|word| titles.get(word)) {
+            results.extend(self.preceding.get(&first).into_iter().flatten());
+        }
+        results
+    }
+
+    // Number of distinct words with a completion
+    pub fn len(&self) -> usize {
+        self.following.len() + self.preceding.keys().filter(|word| !self.following.contains_key(word)).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.following.is_empty()
+    }
+}
@@ -0,0 +1,69 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// How many items are waiting for a worker, handlers check it to give their worker up sooner
+#[derive(Clone, Default)]
+pub struct Backlog(Arc<AtomicUsize>);
+
+impl Backlog {
+    pub fn waiting(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/**
+ * A fixed number of worker threads running handler on items from a bounded queue, so only
+ * so many queries run (and hold memory) at once. Workers exit once the pool is dropped, a panicking
+ * handler only loses its own item.
+ */
+pub struct WorkerPool<T: Send + 'static> {
+    sender: SyncSender<T>,
+    // Items submitted but not picked up by a worker yet
+    queued: Backlog
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new<F: Fn(T, &Backlog) + Send + Sync + 'static>(workers: usize, queue_size: usize, handler: F) -> WorkerPool<T> {
+        let (sender, receiver) = sync_channel::<T>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Backlog::default();
+        let handler = Arc::new(handler);
+        for _ in 0..workers {
+            let (receiver, queued, handler) = (receiver.clone(), queued.clone(), handler.clone());
+            thread::spawn(move || loop {
+                // The lock is only held while waiting, not while handling
+                let item = match receiver.lock().unwrap().recv() {
+                    Ok(item) => item,
+                    Err(_) => break
+                };
+                queued.0.fetch_sub(1, Ordering::Relaxed);
+                // The panic is already reported, the worker carries on with the next item
+                if catch_unwind(AssertUnwindSafe(|| handler(item, &queued))).is_err() {
+                    eprintln!("Worker recovered from a panicking handler");
+                }
+            });
+        }
+        WorkerPool{sender, queued}
+    }
+
+    // Queues item for the next free worker, handing it back when the queue is full
+    pub fn try_submit(&self, item: T) -> Result<(), T> {
+        // Counted first so a worker taking it straight away can't take the count below 0
+        self.queued.0.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => {
+                self.queued.0.fetch_sub(1, Ordering::Relaxed);
+                Err(item)
+            }
+        }
+    }
+
+    // Items waiting for a worker, a busy pool has some
+    pub fn queued(&self) -> usize {
+        self.queued.waiting()
+    }
+}
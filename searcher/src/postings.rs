@@ -0,0 +1,153 @@
+extern crate memmap;
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use memmap::Mmap;
+
+/**
+ * Postings file layout (all fixed width integers little endian):
+ *
+ * - header: magic, number of lists, byte position of the offsets table
+ * - data: each list as varint encoded deltas between sorted values
+ * - offsets: (count + 1) x u64 byte offsets of each list within data
+ *
+ * The FST maps a stem to its list ordinal, lists are only decoded when a stem is looked up.
+ */
+const MAGIC: &[u8; 8] = b"POSTINGS";
+const HEADER_LEN: u64 = 24;
+
+pub struct PostingsWriter {
+    writer: BufWriter<File>,
+    offsets: Vec<u64>,
+    data_len: u64
+}
+
+pub struct Postings {
+    mmap: Mmap,
+    count: usize,
+    offsets_pos: usize
+}
+
+pub fn encode_varint(mut value: u64, output: &mut Vec<u8>) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn corrupt(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Returns the decoded value and the number of bytes consumed, fails on a cut off or over 64 bit value
+pub fn decode_varint(bytes: &[u8]) -> io::Result<(u64, usize)> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        // Only the lowest bit of a 10th byte still fits
+        if shift > 63 || (shift == 63 && byte & 0x7e != 0) {
+            return Err(corrupt("varint over 64 bits"));
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+        shift += 7;
+    }
+    Err(corrupt("varint cut off"))
+}
+
+impl PostingsWriter {
+    pub fn create(file_path: &str) -> io::Result<PostingsWriter> {
+        let mut writer = BufWriter::new(File::create(file_path)?);
+        // Header is rewritten by finish once the offsets position is known
+        writer.write_all(&[0u8; HEADER_LEN as usize])?;
+        Ok(PostingsWriter{writer, offsets: vec![0], data_len: 0})
+    }
+
+    // Values must be strictly ascending, which they are when gathered in line order
+    pub fn push(&mut self, values: &[u64]) -> io::Result<()> {
+        let mut encoded: Vec<u8> = Vec::with_capacity(values.len() * 2);
+        let mut previous: u64 = 0;
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 && *value <= previous {
+                return Err(corrupt(&format!("postings list not strictly ascending ({} after {})", value, previous)));
+            }
+            encode_varint(value - previous, &mut encoded);
+            previous = *value;
+        }
+        self.writer.write_all(&encoded)?;
+        self.data_len += encoded.len() as u64;
+        self.offsets.push(self.data_len);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let count = (self.offsets.len() - 1) as u64;
+        let offsets_pos = HEADER_LEN + self.data_len;
+        for offset in &self.offsets {
+            self.writer.write_all(&offset.to_le_bytes())?;
+        }
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(MAGIC)?;
+        self.writer.write_all(&count.to_le_bytes())?;
+        self.writer.write_all(&offsets_pos.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub fn open_postings(file_path: &str) -> io::Result<Postings> {
+    let mmap = unsafe { Mmap::map(&File::open(file_path)?)? };
+    if mmap.len() < HEADER_LEN as usize || &mmap[0..8] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a postings file", file_path)));
+    }
+    let count = u64::from_le_bytes(mmap[8..16].try_into().unwrap());
+    let offsets_pos = u64::from_le_bytes(mmap[16..24].try_into().unwrap());
+    let end = count.checked_add(1).and_then(|offsets| offsets.checked_mul(8)).and_then(|len| len.checked_add(offsets_pos));
+    if offsets_pos < HEADER_LEN || end != Some(mmap.len() as u64) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is truncated", file_path)));
+    }
+    Ok(Postings{mmap, count: count as usize, offsets_pos: offsets_pos as usize})
+}
+
+impl Postings {
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn offset(&self, index: usize) -> usize {
+        let pos = self.offsets_pos + index * 8;
+        u64::from_le_bytes(self.mmap[pos..pos + 8].try_into().unwrap()) as usize
+    }
+
+    // Decodes a single list, fails if the file is corrupt
+    pub fn get(&self, index: usize) -> io::Result<Vec<u64>> {
+        if index >= self.count {
+            return Err(corrupt("list index out of range"));
+        }
+        let start = HEADER_LEN as usize + self.offset(index);
+        let end = HEADER_LEN as usize + self.offset(index + 1);
+        if start > end || end > self.offsets_pos {
+            return Err(corrupt("list offsets out of range"));
+        }
+        let bytes = &self.mmap[start..end];
+        let mut values: Vec<u64> = Vec::new();
+        let mut pos = 0;
+        let mut previous: u64 = 0;
+        while pos < bytes.len() {
+            let (delta, consumed) = decode_varint(&bytes[pos..])?;
+            previous = previous.checked_add(delta).ok_or_else(|| corrupt("value over 64 bits"))?;
+            values.push(previous);
+            pos += consumed;
+        }
+        Ok(values)
+    }
+}
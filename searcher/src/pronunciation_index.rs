@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::time::Instant;
+
+use super::indexer::IndexError;
+use super::titles::{ArticleId, TitleDict};
+
+/**
+ * Pronunciations loaded from a CMUdict style file, one pronunciation per line:
+ * - WORD  W ER1 D
+ * - WORD(1)  W ER0 D (alternate pronunciations)
+ * - ;;; comments
+ *
+ * Homophones share phonemes (stress ignored), rhymes share everything from the
+ * last stressed vowel on.
+ */
+#[derive(Default)]
+pub struct PronunciationIndex {
+    // word -> (phonemes, rhyme) for each of its pronunciations
+    pronunciations: HashMap<ArticleId, Vec<(String, String)>>,
+    by_phonemes: HashMap<String, Vec<ArticleId>>,
+    by_rhyme: HashMap<String, Vec<ArticleId>>
+}
+
+fn strip_stress(phoneme: &str) -> &str {
+    phoneme.trim_end_matches(|c: char| c.is_ascii_digit())
+}
+
+/**
+ * Last primary stressed vowel and everything after it (e.g. "AY1 T" for "night"),
+ * falling back to secondary stress and then to the last vowel
+ */
+pub fn rhyme_key(phonemes: &[&str]) -> String {
+    let last_with = |marks: &[char]| phonemes.iter().rposition(|p| p.ends_with(|c: char| marks.contains(&c)));
+    let start = last_with(&['1'])
+        .or_else(|| last_with(&['2']))
+        .or_else(|| last_with(&['0']))
+        .unwrap_or(0);
+    phonemes[start..].iter().map(|p| strip_stress(p)).collect::<Vec<&str>>().join(" ")
+}
+
+pub fn generate_pronunciation_index(file_path: &str, titles: &TitleDict) -> Result<PronunciationIndex, IndexError> {
+    let mut index = PronunciationIndex::default();
+    let file = File::open(file_path).map_err(|e| IndexError::io(file_path, e))?;
+    let mut counter = 0;
+    let process_start = Instant::now();
+    // The original CMUdict has a few Latin-1 lines, so don't insist on UTF-8
+    for (line_index, line) in io::BufReader::new(file).split(b'\n').enumerate() {
+        let bytes = line.map_err(|e| IndexError::line(file_path, line_index + 1, e))?;
+        let entry = String::from_utf8_lossy(&bytes);
+        let entry = entry.trim();
+        if entry.is_empty() || entry.starts_with(";;;") {
+            continue;
+        }
+        let mut fields = entry.split_whitespace();
+        let raw_word = fields.next().unwrap();
+        let phonemes: Vec<&str> = fields.collect();
+        if phonemes.is_empty() {
+            return Err(IndexError::Shape{file: file_path.to_string(), line: line_index + 1, message: format!("{} has no phonemes", raw_word)});
+        }
+        // WORD(1) is an alternate pronunciation of WORD
+        let word = match raw_word.find('(') {
+            Some(paren) if raw_word.ends_with(')') && paren > 0 => &raw_word[..paren],
+            _ => raw_word
+        };
+        let word_id = titles.intern(&word.to_lowercase());
+        let phoneme_key = phonemes.iter().map(|p| strip_stress(p)).collect::<Vec<&str>>().join(" ");
+        let rhyme = rhyme_key(&phonemes);
+        index.by_phonemes.entry(phoneme_key.clone()).or_default().push(word_id);
+        index.by_rhyme.entry(rhyme.clone()).or_default().push(word_id);
+        index.pronunciations.entry(word_id).or_default().push((phoneme_key, rhyme));
+        counter += 1;
+        if counter % 1000000 == 0 {
+            println!("counter: {}", counter);
+        }
+    }
+    println!("Finished: {} seconds", process_start.elapsed().as_secs());
+    Ok(index)
+}
+
+impl PronunciationIndex {
+    // Words pronounced exactly like word under any of its pronunciations, word included
+    pub fn homophones(&self, word: ArticleId) -> Vec<ArticleId> {
+        let mut results: Vec<ArticleId> = Vec::new();
+        for (phonemes, _) in self.pronunciations.get(&word).into_iter().flatten() {
+            results.extend_from_slice(&self.by_phonemes[phonemes]);
+        }
+        results.sort_unstable();
+        results.dedup();
+        results
+    }
+
+    // Words rhyming with word, a word doesn't rhyme with itself
+    pub fn rhymes(&self, word: ArticleId) -> Vec<ArticleId> {
+        let mut results: Vec<ArticleId> = Vec::new();
+        for (_, rhyme) in self.pronunciations.get(&word).into_iter().flatten() {
+            results.extend(self.by_rhyme[rhyme].iter().filter(|other| **other != word));
+        }
+        results.sort_unstable();
+        results.dedup();
+        results
+    }
+
+    // Number of distinct words
+    pub fn len(&self) -> usize {
+        self.pronunciations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pronunciations.is_empty()
+    }
+}
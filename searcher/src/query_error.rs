@@ -0,0 +1,94 @@
+extern crate serde_json;
+
+use std::fmt;
+
+use serde_json::{json, Value};
+
+use super::budget::Stop;
+
+/**
+ * Everything that can go wrong with a query, answered as
+ * {"error": {"code", "message", "stage"}} with a matching HTTP status
+ */
+#[derive(Debug, PartialEq)]
+pub enum QueryError {
+    // Body isn't JSON (or isn't an object)
+    InvalidJson{message: String},
+    // A field is missing or has the wrong type or value
+    InvalidField{field: String, message: String},
+    UnknownStage{stage: String, available: Vec<String>},
+    // No terms, or a blank one
+    EmptyTerms,
+    // e.g. WikiArticleExact first, or WikiAllStem after another stage
+    StageOrder{stage: String, position: usize, first: bool},
+    // The first stage can't search a term, e.g. a bad Pattern
+    InvalidTerm{stage: String, term: String, message: String},
+    // Cancelled, timed out or over max_size before any stage finished
+    OutOfBudget{stop: Stop, stage: String, position: usize, max_size: usize},
+    // A stage couldn't run with these terms
+    StageFailed{stage: String, message: String},
+    // Paging through results that are no longer kept
+    ExpiredCursor{cursor: u64}
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::InvalidJson{message} => write!(f, "query isn't valid JSON: {}", message),
+            QueryError::InvalidField{field, message} => write!(f, "{} {}", field, message),
+            QueryError::UnknownStage{stage, available} => write!(f, "unknown stage {} (available: {})", stage, available.join(", ")),
+            QueryError::EmptyTerms => write!(f, "terms must be a non-empty list of non-blank strings"),
+            QueryError::StageOrder{stage, position, first: true} => write!(f, "{} (#{}) can't be the first stage", stage, position),
+            QueryError::StageOrder{stage, position, first: false} => write!(f, "{} (#{}) can only be the first stage", stage, position),
+            QueryError::InvalidTerm{stage, term, message} => write!(f, "{} can't search {}: {}", stage, term, message),
+            QueryError::OutOfBudget{stop: Stop::TooLarge(size), stage, position, max_size} => write!(f, "maximum working size {} exceeded max {} for stage: {} (#{})", size, max_size, stage, position),
+            QueryError::OutOfBudget{stop, stage, position, ..} => write!(f, "{} in stage: {} (#{})", stop, stage, position),
+            QueryError::StageFailed{stage, message} => write!(f, "{} failed: {}", stage, message),
+            QueryError::ExpiredCursor{cursor} => write!(f, "results {} have expired, run the query again", cursor)
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl QueryError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            QueryError::InvalidJson{..} => "invalid_json",
+            QueryError::InvalidField{..} => "invalid_field",
+            QueryError::UnknownStage{..} => "unknown_stage",
+            QueryError::EmptyTerms => "empty_terms",
+            QueryError::StageOrder{..} => "stage_order",
+            QueryError::InvalidTerm{..} => "invalid_term",
+            QueryError::OutOfBudget{stop, ..} => stop.code(),
+            QueryError::StageFailed{..} => "stage_failed",
+            QueryError::ExpiredCursor{..} => "expired_cursor"
+        }
+    }
+
+    // Bad requests are 400, valid queries that can't be answered 422
+    pub fn status(&self) -> u16 {
+        match self {
+            QueryError::OutOfBudget{..} | QueryError::StageFailed{..} => 422,
+            QueryError::ExpiredCursor{..} => 410,
+            _ => 400
+        }
+    }
+
+    pub fn stage(&self) -> Option<&str> {
+        match self {
+            QueryError::UnknownStage{stage, ..} | QueryError::StageOrder{stage, ..} | QueryError::InvalidTerm{stage, ..}
+            | QueryError::OutOfBudget{stage, ..} | QueryError::StageFailed{stage, ..} => Some(stage),
+            _ => None
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        error_body(self.code(), &self.to_string(), self.stage())
+    }
+}
+
+// The error shape every failed request gets, including ones that never parsed as a query
+pub fn error_body(code: &str, message: &str, stage: Option<&str>) -> Value {
+    json!({"error": {"code": code, "message": message, "stage": stage}})
+}
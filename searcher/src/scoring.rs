@@ -0,0 +1,53 @@
+use super::stages::SharedIndex;
+use super::titles::{ArticleId, QueryTitles};
+
+/**
+ * How specific an article is: hub articles ("united states", "list of films") are listed by
+ * a large share of index lines, so finding one says little about the query terms.
+ */
+#[derive(Default)]
+pub struct Specificity {
+    indexes: Vec<SharedIndex>,
+    // Lines across all indexes
+    total_lines: u64
+}
+
+impl Specificity {
+    /**
+     * Each index should be given once, even when it serves several stages
+     */
+    pub fn new(indexes: Vec<SharedIndex>) -> Specificity {
+        let total_lines = indexes.iter().map(|index| index.num_lines()).sum();
+        Specificity{indexes, total_lines}
+    }
+
+    /**
+     * Chance that one random association is article, weighted by in-degree (add one smoothed).
+     * None when no index knows its in-degrees.
+     */
+    pub fn chance(&self, article: ArticleId, titles: &QueryTitles) -> Option<f64> {
+        if self.total_lines == 0 {
+            return None;
+        }
+        let in_degree: u64 = self.indexes.iter().map(|index| index.in_degree(article, titles)).sum();
+        Some((in_degree + 1) as f64 / (self.total_lines + 1) as f64)
+    }
+
+    pub fn total_lines(&self) -> u64 {
+        self.total_lines
+    }
+}
+
+/**
+ * Evidence (in nats) that a term reaching an article is no coincidence: minus the log of the chance
+ * that term_associations random associations include the article at least once. Hub articles and
+ * terms reaching huge numbers of associations both score close to 0.
+ */
+pub fn surprisal(chance: f64, term_associations: usize) -> f64 {
+    // 1 - (1 - chance)^n, without losing precision for tiny chances
+    let coincidence = -((term_associations as f64) * (-chance).ln_1p()).exp_m1();
+    if coincidence <= 0.0 {
+        return 0.0;
+    }
+    (-coincidence.ln()).max(0.0)
+}
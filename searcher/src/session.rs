@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/**
+ * Recently computed query results by cursor, so paging through them doesn't rerun every stage.
+ * Entries expire after ttl without use, and the least recently used go first beyond capacity.
+ */
+pub struct ResultCache<T> {
+    inner: Mutex<CacheInner<T>>,
+    capacity: usize,
+    ttl: Duration
+}
+
+struct CacheInner<T> {
+    next_cursor: u64,
+    // cursor -> (results, last used)
+    entries: HashMap<u64, (Arc<T>, Instant)>
+}
+
+impl<T> ResultCache<T> {
+    pub fn new(capacity: usize, ttl: Duration) -> ResultCache<T> {
+        // Cursors from before a restart shouldn't find someone else's results
+        let first_cursor = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs() << 20).unwrap_or(0);
+        ResultCache{
+            inner: Mutex::new(CacheInner{next_cursor: first_cursor, entries: HashMap::new()}),
+            capacity,
+            ttl
+        }
+    }
+
+    pub fn insert(&self, results: T) -> (u64, Arc<T>) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let ttl = self.ttl;
+        inner.entries.retain(|_, (_, last_used)| now.duration_since(*last_used) < ttl);
+        while !inner.entries.is_empty() && inner.entries.len() >= self.capacity {
+            let oldest = *inner.entries.iter().min_by_key(|(_, (_, last_used))| *last_used).unwrap().0;
+            inner.entries.remove(&oldest);
+        }
+        let cursor = inner.next_cursor;
+        inner.next_cursor += 1;
+        let results = Arc::new(results);
+        inner.entries.insert(cursor, (results.clone(), now));
+        (cursor, results)
+    }
+
+    pub fn get(&self, cursor: u64) -> Option<Arc<T>> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match inner.entries.get_mut(&cursor) {
+            Some((results, last_used)) if now.duration_since(*last_used) < self.ttl => {
+                *last_used = now;
+                Some(results.clone())
+            },
+            Some(_) => {
+                inner.entries.remove(&cursor);
+                None
+            },
+            None => None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
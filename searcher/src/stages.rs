@@ -0,0 +1,683 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::anagram::AnagramIndex;
+use super::budget::Budget;
+use super::indexer::Searchable;
+use super::lexicon::Lexicon;
+use super::pattern::{KeyPattern, LetterPattern};
+use super::phonetic::PhoneticIndex;
+use super::phrases::PhraseIndex;
+use super::pronunciation_index::PronunciationIndex;
+use super::scoring::Specificity;
+use super::substring::SubstringIndex;
+use super::synonym_index;
+use super::synonym_index::SynonymIndex;
+use super::titles::{ArticleId, QueryTitles, TitleDict};
+use super::transforms::{SpellingIndex, Transform, TransformKind};
+
+pub type SharedIndex = Arc<dyn Searchable + Send + Sync>;
+
+// This struct stores 1) original search term 2) the match
+// e.g. book -> book of job
+// this is to help us retrace our steps through association phases
+// weight is how good the chain so far is, 1.0 for exact steps, multiplied along the chain
+// detail is anything else the step needs to be explained, e.g. where the term was hidden
+#[derive(Debug)]
+pub struct SearchMatch {
+    pub search_term: ArticleId,
+    pub search_match: ArticleId,
+    pub weight: f64,
+    pub detail: Option<MatchDetail>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchDetail {
+    // Letter position of the search term inside search_match
+    Position(u32),
+    // How the search term was rewritten into the result
+    Transform(Transform)
+}
+
+// query term -> article -> how we got there
+pub type AssociationDict = HashMap<String, HashMap<ArticleId, SearchMatch>>;
+
+/**
+ * One step of a query. The first stage starts from the query terms, every later stage
+ * expands each association the previous stage found. Errors are shown to the user.
+ */
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Both give up with budget's error once the query is out of budget
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String>;
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String>;
+
+    // Rejects a query term the stage can't search before the query runs, only asked of the first stage
+    fn check_term(&self, _term: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Whether the stage can search the query terms themselves, i.e. run first
+    fn can_start(&self) -> bool {
+        true
+    }
+
+    // Whether the stage can search what an earlier stage found
+    fn can_follow(&self) -> bool {
+        true
+    }
+}
+
+// Entries across every term of an association dict
+pub fn sum_subentries(map_of_maps: &AssociationDict) -> usize {
+    map_of_maps.values().map(HashMap::len).sum()
+}
+
+// Keeps the best weighted way of reaching each article, ties go to the later match
+fn keep_best(entry: &mut HashMap<ArticleId, SearchMatch>, article: ArticleId, found: SearchMatch) {
+    if entry.get(&article).map(|existing| existing.weight > found.weight).unwrap_or(false) {
+        return;
+    }
+    entry.insert(article, found);
+}
+
+/**
+ * expand_initial for stages that search each query term on its own. lookup gets the term and its
+ * id and returns article -> how it was found, weighted by how good that one step is.
+ */
+fn expand_terms<F>(terms: &[String], titles: &QueryTitles, budget: &Budget, mut lookup: F) -> Result<AssociationDict, String>
+    where F: FnMut(&str, ArticleId) -> Result<Vec<(ArticleId, SearchMatch)>, String> {
+    let mut association_dict: AssociationDict = HashMap::new();
+    for term in terms {
+        budget.check(sum_subentries(&association_dict))?;
+        let found = lookup(term, titles.id(term))?;
+        let entry = association_dict.entry(term.to_string()).or_default();
+        for (article, search_match) in found {
+            keep_best(entry, article, search_match);
+        }
+    }
+    Ok(association_dict)
+}
+
+/**
+ * expand for stages that search each association on its own, lookup works like for expand_terms.
+ * Weights are multiplied along the chain.
+ */
+fn expand_associations<F>(associations: &AssociationDict, titles: &QueryTitles, budget: &Budget, mut lookup: F) -> Result<AssociationDict, String>
+    where F: FnMut(&str, ArticleId) -> Result<Vec<(ArticleId, SearchMatch)>, String> {
+    let mut association_dict: AssociationDict = HashMap::new();
+    for (term, subassociations) in associations.iter() {
+        let size = sum_subentries(&association_dict);
+        let entry = association_dict.entry(term.to_string()).or_default();
+        for (orig_search_child, parent) in subassociations.iter() {
+            budget.check(size + entry.len())?;
+            for (article, mut search_match) in lookup(&titles.resolve(*orig_search_child), *orig_search_child)? {
+                search_match.weight *= parent.weight;
+                keep_best(entry, article, search_match);
+            }
+        }
+    }
+    Ok(association_dict)
+}
+
+// What a built-in stage searches, so the config can be checked before anything is loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StageInput {
+    Associations,
+    WordLists,
+    Lexicons,
+    Pronunciations
+}
+
+pub fn builtin_stage_input(name: &str) -> Option<StageInput> {
+    match name {
+        "WikiAllStem" | "WikiArticleStem" | "WikiArticleExact" | "Fuzzy" | "Prefix" | "Pattern" => Some(StageInput::Associations),
+        "Synonym" | "Homophone" => Some(StageInput::WordLists),
+        "Anagram" | "Phonetic" | "Substring" | "Transform" | "PhraseCompletion" => Some(StageInput::Lexicons),
+        "TrueHomophone" | "Rhyme" => Some(StageInput::Pronunciations),
+        _ => None
+    }
+}
+
+// Stages that walk the fst itself, other association backends can't serve them
+pub fn needs_fst(name: &str) -> bool {
+    matches!(name, "Fuzzy" | "Prefix" | "Pattern")
+}
+
+/**
+ * Loaded indexes by the stage name they were configured for
+ */
+#[derive(Default)]
+pub struct StageSources {
+    pub associations: HashMap<String, Vec<SharedIndex>>,
+    pub word_lists: HashMap<String, Vec<Arc<SynonymIndex>>>,
+    pub lexicons: HashMap<String, Vec<Arc<Lexicon>>>,
+    pub pronunciations: HashMap<String, Vec<Arc<PronunciationIndex>>>
+}
+
+/**
+ * Searches association indexes, later indexes win when several find the same article.
+ * The query terms are searched word by word, later stages search whole titles.
+ */
+pub struct AssociationStage {
+    name: String,
+    indexes: Vec<SharedIndex>,
+    allow_initial: bool,
+    allow_expand: bool
+}
+
+impl AssociationStage {
+    pub fn new(name: &str, indexes: Vec<SharedIndex>, allow_initial: bool, allow_expand: bool) -> AssociationStage {
+        AssociationStage{name: name.to_string(), indexes, allow_initial, allow_expand}
+    }
+}
+
+impl Stage for AssociationStage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn can_start(&self) -> bool {
+        self.allow_initial
+    }
+
+    fn can_follow(&self) -> bool {
+        self.allow_expand
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        if !self.allow_initial {
+            return Err(format!("{} can't be the first stage", self.name));
+        }
+        expand_terms(terms, titles, budget, |term, term_id| {
+            Ok(self.indexes.iter().flat_map(|index| index.search(term, 1, false, titles))
+                .map(|(search_child, search_match)| (search_child, SearchMatch{search_term: term_id, search_match, weight: 1.0, detail: None}))
+                .collect())
+        })
+    }
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        if !self.allow_expand {
+            return Err(format!("{} can only be the first stage", self.name));
+        }
+        // search returns <result entry, what matched that entry's key>
+        // since this is subfind we do 0 stemming and include the whole string
+        expand_associations(associations, titles, budget, |text, search_term| {
+            Ok(self.indexes.iter().flat_map(|index| index.search(text, 0, true, titles))
+                .map(|(search_child, search_match)| (search_child, SearchMatch{search_term, search_match, weight: 1.0, detail: None}))
+                .collect())
+        })
+    }
+}
+
+/**
+ * Looks words up in comma separated word lists (synonyms, homophones)
+ */
+pub struct WordListStage {
+    name: String,
+    lists: Vec<Arc<SynonymIndex>>
+}
+
+impl WordListStage {
+    pub fn new(name: &str, lists: Vec<Arc<SynonymIndex>>) -> WordListStage {
+        WordListStage{name: name.to_string(), lists}
+    }
+}
+
+impl Stage for WordListStage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_terms(terms, titles, budget, |_, term_id| {
+            // Need to map syn -> syn otherwise if we use 'term' we'll only get the last entry
+            Ok(self.lists.iter().flat_map(|list| synonym_index::search_synonym_index(term_id, list))
+                .map(|(syn, _)| (syn, SearchMatch{search_term: term_id, search_match: term_id, weight: 1.0, detail: None}))
+                .collect())
+        })
+    }
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_associations(associations, titles, budget, |_, search_term| {
+            Ok(self.lists.iter().flat_map(|list| synonym_index::search_synonym_index(search_term, list))
+                .map(|(search_child, search_match)| (search_child, SearchMatch{search_term, search_match, weight: 1.0, detail: None}))
+                .collect())
+        })
+    }
+}
+
+/**
+ * Words and titles spelled with the same letters as the term, or one letter more/less
+ */
+pub struct AnagramStage {
+    name: String,
+    index: Arc<AnagramIndex>,
+    letter_delta: i32
+}
+
+impl AnagramStage {
+    pub fn new(name: &str, index: Arc<AnagramIndex>, letter_delta: i32) -> AnagramStage {
+        AnagramStage{name: name.to_string(), index, letter_delta}
+    }
+
+    fn lookup(&self, text: &str, search_term: ArticleId) -> Vec<(ArticleId, SearchMatch)> {
+        self.index.search(text, self.letter_delta).into_iter()
+            .map(|anagram| (anagram, SearchMatch{search_term, search_match: search_term, weight: 1.0, detail: None}))
+            .collect()
+    }
+}
+
+impl Stage for AnagramStage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_terms(terms, titles, budget, |term, term_id| Ok(self.lookup(term, term_id)))
+    }
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_associations(associations, titles, budget, |text, search_term| Ok(self.lookup(text, search_term)))
+    }
+}
+
+/**
+ * Like WikiArticleStem but matches keys within distance edits of the search, for typos
+ * and "change one letter" puzzles. A match n edits away is weighted 1 / (1 + n).
+ */
+pub struct FuzzyStage {
+    name: String,
+    indexes: Vec<SharedIndex>,
+    distance: u32
+}
+
+impl FuzzyStage {
+    pub fn new(name: &str, indexes: Vec<SharedIndex>, distance: u32) -> FuzzyStage {
+        FuzzyStage{name: name.to_string(), indexes, distance}
+    }
+
+    fn lookup(&self, text: &str, search_term: ArticleId, max_group: usize, include_whole: bool, titles: &QueryTitles) -> Result<Vec<(ArticleId, SearchMatch)>, String> {
+        let mut found = Vec::new();
+        for index in &self.indexes {
+            for (search_child, (search_match, edits)) in index.search_fuzzy(text, max_group, include_whole, self.distance, titles)? {
+                found.push((search_child, SearchMatch{search_term, search_match, weight: edit_weight(edits), detail: None}));
+            }
+        }
+        Ok(found)
+    }
+}
+
+fn edit_weight(edits: u32) -> f64 {
+    1.0 / (1.0 + edits as f64)
+}
+
+impl Stage for FuzzyStage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_terms(terms, titles, budget, |term, term_id| self.lookup(term, term_id, 1, false, titles))
+    }
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_associations(associations, titles, budget, |text, search_term| self.lookup(text, search_term, 0, true, titles))
+    }
+}
+
+// Prefix and Pattern searches matching more keys than this fail instead of flooding the query
+pub const MAX_PATTERN_KEYS: usize = 10000;
+
+/**
+ * Treats each query term as a key prefix (Prefix) or a LetterPattern (Pattern) and finds
+ * the articles of every matching key. Keys are stems, so "moon" also finds "moonlight".
+ */
+pub struct KeyPatternStage {
+    name: String,
+    indexes: Vec<SharedIndex>,
+    prefix: bool
+}
+
+impl KeyPatternStage {
+    pub fn new(name: &str, indexes: Vec<SharedIndex>, prefix: bool) -> KeyPatternStage {
+        KeyPatternStage{name: name.to_string(), indexes, prefix}
+    }
+}
+
+impl Stage for KeyPatternStage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn can_follow(&self) -> bool {
+        false
+    }
+
+    fn check_term(&self, term: &str) -> Result<(), String> {
+        if !self.prefix {
+            LetterPattern::parse(term)?;
+        }
+        Ok(())
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_terms(terms, titles, budget, |term, term_id| {
+            let pattern = if self.prefix {
+                KeyPattern::Prefix(term.trim().to_lowercase())
+            } else {
+                KeyPattern::Pattern(LetterPattern::parse(term)?)
+            };
+            let mut found = Vec::new();
+            for index in &self.indexes {
+                for (search_child, search_match) in index.search_keys(&pattern, MAX_PATTERN_KEYS, titles)? {
+                    found.push((search_child, SearchMatch{search_term: term_id, search_match, weight: 1.0, detail: None}));
+                }
+            }
+            Ok(found)
+        })
+    }
+
+    fn expand(&self, _associations: &AssociationDict, _titles: &QueryTitles, _budget: &Budget) -> Result<AssociationDict, String> {
+        Err(format!("{} can only be the first stage", self.name))
+    }
+}
+
+/**
+ * Words and titles that sound like the term, near misses get a lower weight
+ */
+pub struct PhoneticStage {
+    name: String,
+    index: PhoneticIndex
+}
+
+impl PhoneticStage {
+    pub fn new(name: &str, index: PhoneticIndex) -> PhoneticStage {
+        PhoneticStage{name: name.to_string(), index}
+    }
+
+    fn lookup(&self, text: &str, search_term: ArticleId) -> Vec<(ArticleId, SearchMatch)> {
+        self.index.search(text).into_iter()
+            .map(|(sound_alike, weight)| (sound_alike, SearchMatch{search_term, search_match: search_term, weight, detail: None}))
+            .collect()
+    }
+}
+
+impl Stage for PhoneticStage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_terms(terms, titles, budget, |term, term_id| Ok(self.lookup(term, term_id)))
+    }
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_associations(associations, titles, budget, |text, search_term| Ok(self.lookup(text, search_term)))
+    }
+}
+
+/**
+ * Titles hiding the term as a contiguous letter run, e.g. "car" in "scarpet" or "Oscar Pet".
+ * search_match is the title itself, with the position of the hidden letters.
+ */
+pub struct SubstringStage {
+    name: String,
+    index: SubstringIndex
+}
+
+impl SubstringStage {
+    pub fn new(name: &str, index: SubstringIndex) -> SubstringStage {
+        SubstringStage{name: name.to_string(), index}
+    }
+
+    fn lookup(&self, text: &str, search_term: ArticleId) -> Vec<(ArticleId, SearchMatch)> {
+        self.index.search(text).into_iter()
+            .map(|(container, position)| (container, SearchMatch{search_term, search_match: container, weight: 1.0, detail: Some(MatchDetail::Position(position))}))
+            .collect()
+    }
+}
+
+impl Stage for SubstringStage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_terms(terms, titles, budget, |term, term_id| Ok(self.lookup(term, term_id)))
+    }
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_associations(associations, titles, budget, |text, search_term| Ok(self.lookup(text, search_term)))
+    }
+}
+
+/**
+ * Real words and titles one word play transform away from the term, e.g. Reverse finds
+ * "desserts" for "stressed". The chain records the transform applied.
+ */
+pub struct TransformStage {
+    index: Arc<SpellingIndex>,
+    kind: TransformKind
+}
+
+impl TransformStage {
+    pub fn new(index: Arc<SpellingIndex>, kind: TransformKind) -> TransformStage {
+        TransformStage{index, kind}
+    }
+
+    fn lookup(&self, text: &str, search_term: ArticleId) -> Vec<(ArticleId, SearchMatch)> {
+        self.index.search(self.kind, text).into_iter()
+            .map(|(word, transform)| (word, SearchMatch{search_term, search_match: search_term, weight: 1.0, detail: Some(MatchDetail::Transform(transform))}))
+            .collect()
+    }
+}
+
+impl Stage for TransformStage {
+    fn name(&self) -> &str {
+        self.kind.name()
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_terms(terms, titles, budget, |term, term_id| Ok(self.lookup(term, term_id)))
+    }
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_associations(associations, titles, budget, |text, search_term| Ok(self.lookup(text, search_term)))
+    }
+}
+
+/**
+ * Words completing the term into a two word phrase from some title, e.g. "mortar" for "brick"
+ * (brick and mortar) and for "pestle" (mortar and pestle). search_match is that title.
+ */
+pub struct PhraseCompletionStage {
+    name: String,
+    index: PhraseIndex
+}
+
+impl PhraseCompletionStage {
+    pub fn new(name: &str, index: PhraseIndex) -> PhraseCompletionStage {
+        PhraseCompletionStage{name: name.to_string(), index}
+    }
+
+    fn lookup(&self, text: &str, search_term: ArticleId, titles: &QueryTitles) -> Vec<(ArticleId, SearchMatch)> {
+        self.index.completions(text, titles.shared()).into_iter()
+            .map(|(completion, title)| (completion, SearchMatch{search_term, search_match: title, weight: 1.0, detail: None}))
+            .collect()
+    }
+}
+
+impl Stage for PhraseCompletionStage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_terms(terms, titles, budget, |term, term_id| Ok(self.lookup(term, term_id, titles)))
+    }
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_associations(associations, titles, budget, |text, search_term| Ok(self.lookup(text, search_term, titles)))
+    }
+}
+
+/**
+ * Words pronounced the same (rhymes: false) or rhyming (rhymes: true) according to
+ * pronunciation dictionaries, words missing from the dictionaries find nothing
+ */
+pub struct PronunciationStage {
+    name: String,
+    indexes: Vec<Arc<PronunciationIndex>>,
+    rhymes: bool
+}
+
+impl PronunciationStage {
+    pub fn new(name: &str, indexes: Vec<Arc<PronunciationIndex>>, rhymes: bool) -> PronunciationStage {
+        PronunciationStage{name: name.to_string(), indexes, rhymes}
+    }
+
+    fn lookup(&self, word: &str, titles: &QueryTitles) -> Vec<ArticleId> {
+        let mut results: Vec<ArticleId> = Vec::new();
+        // Dictionary words are interned lower case
+        if let Some(word_id) = titles.get(&word.trim().to_lowercase()) {
+            for index in &self.indexes {
+                if self.rhymes {
+                    results.extend(index.rhymes(word_id));
+                } else {
+                    results.extend(index.homophones(word_id));
+                }
+            }
+        }
+        results
+    }
+
+    fn matches(&self, text: &str, search_term: ArticleId, titles: &QueryTitles) -> Vec<(ArticleId, SearchMatch)> {
+        self.lookup(text, titles).into_iter()
+            .map(|word| (word, SearchMatch{search_term, search_match: search_term, weight: 1.0, detail: None}))
+            .collect()
+    }
+}
+
+impl Stage for PronunciationStage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn expand_initial(&self, terms: &[String], titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_terms(terms, titles, budget, |term, term_id| Ok(self.matches(term, term_id, titles)))
+    }
+
+    fn expand(&self, associations: &AssociationDict, titles: &QueryTitles, budget: &Budget) -> Result<AssociationDict, String> {
+        expand_associations(associations, titles, budget, |text, search_term| Ok(self.matches(text, search_term, titles)))
+    }
+}
+
+/**
+ * Stages a query can use, by name
+ */
+#[derive(Default)]
+pub struct StageRegistry {
+    stages: HashMap<String, Arc<dyn Stage>>,
+    // In-degrees of every loaded association index, for scoring
+    specificity: Arc<Specificity>
+}
+
+impl StageRegistry {
+    pub fn new() -> StageRegistry {
+        StageRegistry::default()
+    }
+
+    pub fn register(&mut self, stage: Arc<dyn Stage>) {
+        self.stages.insert(stage.name().to_string(), stage);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Stage>> {
+        self.stages.get(name).cloned()
+    }
+
+    pub fn specificity(&self) -> Arc<Specificity> {
+        self.specificity.clone()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.stages.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /**
+     * Registers the built-in stages for whichever indexes were loaded.
+     * Anagram also registers Anagram+1 and Anagram-1 over the same signatures,
+     * Fuzzy registers Fuzzy1 and Fuzzy2 (one or two edits) and Transform registers
+     * Reverse, DropLetter, AddLetter, ChangeLetter and Shift.
+     */
+    pub fn register_builtins(&mut self, mut sources: StageSources, titles: &TitleDict) {
+        // An index serving several stages only counts once
+        let mut association_indexes: Vec<SharedIndex> = Vec::new();
+        for index in sources.associations.values().flatten() {
+            if !association_indexes.iter().any(|known| Arc::ptr_eq(known, index)) {
+                association_indexes.push(index.clone());
+            }
+        }
+        self.specificity = Arc::new(Specificity::new(association_indexes));
+        for (name, allow_initial, allow_expand) in [("WikiAllStem", true, false),
+                                                    ("WikiArticleStem", true, true),
+                                                    ("WikiArticleExact", false, true)] {
+            if let Some(indexes) = sources.associations.remove(name) {
+                self.register(Arc::new(AssociationStage::new(name, indexes, allow_initial, allow_expand)));
+            }
+        }
+        if let Some(indexes) = sources.associations.remove("Fuzzy") {
+            for (name, distance) in [("Fuzzy1", 1), ("Fuzzy2", 2)] {
+                self.register(Arc::new(FuzzyStage::new(name, indexes.clone(), distance)));
+            }
+        }
+        for (name, prefix) in [("Prefix", true), ("Pattern", false)] {
+            if let Some(indexes) = sources.associations.remove(name) {
+                self.register(Arc::new(KeyPatternStage::new(name, indexes, prefix)));
+            }
+        }
+        for name in ["Synonym", "Homophone"] {
+            if let Some(lists) = sources.word_lists.remove(name) {
+                self.register(Arc::new(WordListStage::new(name, lists)));
+            }
+        }
+        if let Some(lexicons) = sources.lexicons.remove("Anagram") {
+            let index = Arc::new(AnagramIndex::new(&lexicons, titles));
+            println!("Anagram signatures: {}", index.len());
+            for (name, letter_delta) in [("Anagram", 0), ("Anagram+1", 1), ("Anagram-1", -1)] {
+                self.register(Arc::new(AnagramStage::new(name, index.clone(), letter_delta)));
+            }
+        }
+        if let Some(lexicons) = sources.lexicons.remove("Phonetic") {
+            let index = PhoneticIndex::new(&lexicons, titles);
+            println!("Phonetic codes: {}", index.len());
+            self.register(Arc::new(PhoneticStage::new("Phonetic", index)));
+        }
+        if let Some(lexicons) = sources.lexicons.remove("Substring") {
+            let index = SubstringIndex::new(&lexicons, titles);
+            println!("Substring titles: {}", index.len());
+            self.register(Arc::new(SubstringStage::new("Substring", index)));
+        }
+        if let Some(lexicons) = sources.lexicons.remove("Transform") {
+            let index = Arc::new(SpellingIndex::new(&lexicons, titles));
+            println!("Transform spellings: {}", index.len());
+            for kind in TransformKind::all() {
+                self.register(Arc::new(TransformStage::new(index.clone(), kind)));
+            }
+        }
+        if let Some(lexicons) = sources.lexicons.remove("PhraseCompletion") {
+            let index = PhraseIndex::new(&lexicons, titles);
+            println!("PhraseCompletion words: {}", index.len());
+            self.register(Arc::new(PhraseCompletionStage::new("PhraseCompletion", index)));
+        }
+        for (name, rhymes) in [("TrueHomophone", false), ("Rhyme", true)] {
+            if let Some(indexes) = sources.pronunciations.remove(name) {
+                self.register(Arc::new(PronunciationStage::new(name, indexes, rhymes)));
+            }
+        }
+    }
+}
@@ -33,5 +33,5 @@ pub fn generate_stems(term: &str, k: usize, include_whole: bool) -> Vec<String>
     if include_whole {
         stems.insert(words.join(" "));
     }
-    return stems.into_iter().collect();
+    stems.into_iter().collect()
 }
@@ -0,0 +1,119 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use super::lexicon::Lexicon;
+use super::titles::{ArticleId, TitleDict};
+
+// Separates titles in the suffix array text, sorts before every letter
+const SEPARATOR: u8 = 0;
+
+/**
+ * Lower case letters and digits of a title with spaces and punctuation removed,
+ * so "Scar Pet" and "scarpet" both hide "carp"
+ */
+pub fn normalize(text: &str) -> String {
+    text.to_lowercase().chars().filter(|c| c.is_alphanumeric()).collect()
+}
+
+/**
+ * Suffix array over every normalized title, finds titles containing a letter run
+ * anywhere, including across word boundaries
+ */
+pub struct SubstringIndex {
+    // Normalized titles, each followed by SEPARATOR
+    text: Vec<u8>,
+    // Start of every suffix of text, sorted
+    suffixes: Vec<u32>,
+    // (start in text, title) in text order
+    starts: Vec<(u32, ArticleId)>
+}
+
+impl SubstringIndex {
+    pub fn new(lexicons: &[Arc<Lexicon>], titles: &TitleDict) -> SubstringIndex {
+        let mut text: Vec<u8> = Vec::new();
+        let mut starts: Vec<(u32, ArticleId)> = Vec::new();
+        let mut seen: HashSet<ArticleId> = HashSet::new();
+        for lexicon in lexicons {
+            for word in lexicon.words() {
+                if !seen.insert(*word) {
+                    continue;
+                }
+                let normalized = normalize(&titles.resolve(*word));
+                if normalized.is_empty() {
+                    continue;
+                }
+                starts.push((text.len() as u32, *word));
+                text.extend_from_slice(normalized.as_bytes());
+                text.push(SEPARATOR);
+            }
+        }
+        let mut suffixes: Vec<u32> = (0..text.len() as u32).filter(|start| text[*start as usize] != SEPARATOR).collect();
+        suffixes.sort_unstable_by(|a, b| text[*a as usize..].cmp(&text[*b as usize..]));
+        SubstringIndex{text, suffixes, starts}
+    }
+
+    // How the suffix at start compares to suffixes beginning with pattern
+    fn compare(&self, start: u32, pattern: &[u8]) -> Ordering {
+        let suffix = &self.text[start as usize..];
+        suffix[..pattern.len().min(suffix.len())].cmp(pattern)
+    }
+
+    /**
+     * Every title containing text as a contiguous letter run, with the letter position
+     * of its first occurrence (0 based, in the normalized title)
+     */
+    pub fn search(&self, text: &str) -> Vec<(ArticleId, u32)> {
+        let pattern = normalize(text);
+        let mut results: Vec<(ArticleId, u32)> = Vec::new();
+        if pattern.is_empty() {
+            return results;
+        }
+        let pattern = pattern.as_bytes();
+        let first = self.suffixes.partition_point(|start| self.compare(*start, pattern) == Ordering::Less);
+        let last = self.suffixes.partition_point(|start| self.compare(*start, pattern) != Ordering::Greater);
+        let mut first_positions: HashMap<ArticleId, u32> = HashMap::new();
+        for start in &self.suffixes[first..last] {
+            let title_index = self.starts.partition_point(|(title_start, _)| title_start <= start) - 1;
+            let (title_start, title) = self.starts[title_index];
+            let prefix = &self.text[title_start as usize..*start as usize];
+            let position = String::from_utf8_lossy(prefix).chars().count() as u32;
+            let best = first_positions.entry(title).or_insert(position);
+            *best = (*best).min(position);
+        }
+        results.extend(first_positions);
+        results
+    }
+
+    // Number of titles
+    pub fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+}
+
+/**
+ * The title with the hidden letters upper cased, e.g. "sCARpet" for "car" at 1 in "scarpet"
+ */
+pub fn highlight(title: &str, position: u32, length: usize) -> String {
+    let hidden = position as usize..position as usize + length;
+    let mut letter = 0;
+    let mut highlighted = String::new();
+    for c in title.to_lowercase().chars() {
+        if !c.is_alphanumeric() {
+            highlighted.push(c);
+            continue;
+        }
+        if hidden.contains(&letter) {
+            highlighted.extend(c.to_uppercase());
+        } else {
+            highlighted.push(c);
+        }
+        letter += 1;
+    }
+    highlighted
+}
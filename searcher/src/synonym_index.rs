@@ -4,81 +4,67 @@ extern crate serde_json;
 extern crate simd_json; 
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io;
-use std::io::{BufRead};
-use std::path::Path;
 use std::time::Instant;
 
+use super::indexer;
+use super::indexer::IndexError;
+use super::titles::{ArticleId, TitleDict};
 
-// The output is wrapped in a Result to allow matching on errors
-// Returns an Iterator to the Reader of the lines of the file.
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
-}
 
 pub struct SynonymIndex {
-    line_vecs: Vec<Vec<String>>,
-    index: HashMap<String, Vec<usize>>
+    line_vecs: Vec<Vec<ArticleId>>,
+    index: HashMap<ArticleId, Vec<usize>>
 }
 
 /**
- *
+ * Loads a comma separated word list where the first word of each line is the root word
  */
-pub fn generate_synonym_index(file_path: &str) -> SynonymIndex {
+pub fn generate_synonym_index(file_path: &str, titles: &TitleDict) -> Result<SynonymIndex, IndexError> {
 
-    let line_vecs: Vec<Vec<String>> = Vec::new();
-    let index: HashMap<String, Vec<usize>> = HashMap::new();
+    let line_vecs: Vec<Vec<ArticleId>> = Vec::new();
+    let index: HashMap<ArticleId, Vec<usize>> = HashMap::new();
     let mut synonym_index = SynonymIndex{line_vecs, index};
     let mut counter = 0;
     let process_start = Instant::now();
-    if let Ok(lines) = read_lines(file_path) {
-        for line in lines {
-            if let Ok(entry) = line {
-                // Just split by ','
-                let mut all_words: Vec<String> = Vec::new();
-                for word in entry.split(",") {
-                    all_words.push(word.to_ascii_lowercase());
-                }
-                let root_word = all_words.first().unwrap();
-                for _word in &all_words[1..] {
-                    let index_entry = synonym_index.index.entry(root_word.to_string()).or_insert_with(Vec::new);
-                    index_entry.push(counter);
-                }
-                synonym_index.line_vecs.push(all_words);
-                counter += 1;
-                if counter % 1000000 == 0 {
-                    println!("counter: {}", counter);
-                }
-            }
+    for line in indexer::open_lines(file_path)? {
+        let entry = line.map_err(|e| IndexError::line(file_path, counter + 1, e))?;
+        // Just split by ','
+        let mut all_words: Vec<ArticleId> = Vec::new();
+        for word in entry.split(",") {
+            all_words.push(titles.intern(&word.to_ascii_lowercase()));
+        }
+        let root_word = all_words.first().unwrap();
+        for _word in &all_words[1..] {
+            let index_entry = synonym_index.index.entry(*root_word).or_default();
+            index_entry.push(counter);
+        }
+        synonym_index.line_vecs.push(all_words);
+        counter += 1;
+        if counter % 1000000 == 0 {
+            println!("counter: {}", counter);
         }
     }
     println!("Finished: {} seconds", process_start.elapsed().as_secs());
-    return synonym_index;
+    Ok(synonym_index)
 }
 
 
 /**
- * Se
+ * Returns synonym -> term for every synonym of term, the term itself included
  */
-pub fn search_synonym_index(term: &str, index: &SynonymIndex) -> HashMap<String, String> {
+pub fn search_synonym_index(term: ArticleId, index: &SynonymIndex) -> HashMap<ArticleId, ArticleId> {
 
-    let mut result_map: HashMap<String, String> = HashMap::new();
+    let mut result_map: HashMap<ArticleId, ArticleId> = HashMap::new();
 
     // original is always a synonym
-    result_map.insert(term.to_string(), term.to_string());
+    result_map.insert(term, term);
 
-    match index.index.get(term) {
-        Some(line_indexes) => {
-            for line_num in line_indexes {
-                for syn in &(index.line_vecs)[*line_num as usize] {
-                    result_map.insert(syn.to_string(), term.to_string());
-                }
+    if let Some(line_indexes) = index.index.get(&term) {
+        for line_num in line_indexes {
+            for syn in &(index.line_vecs)[*line_num] {
+                result_map.insert(*syn, term);
             }
-        },
-        None => {}
+        }
     }
-    return result_map;
+    result_map
 }
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+// Every article title, association key and word the indexes know about gets one of these
+pub type ArticleId = u32;
+
+// Ids at or above this belong to a single query and are never interned
+const QUERY_ID_BASE: ArticleId = 1 << 31;
+
+/**
+ * Shared title <-> id dictionary. Only indexes intern, while loading. Anything first seen at
+ * query time gets a temporary id from that query's QueryTitles, so queries don't grow it.
+ */
+#[derive(Default)]
+pub struct TitleDict {
+    inner: RwLock<TitleDictInner>
+}
+
+#[derive(Default)]
+struct TitleDictInner {
+    ids: HashMap<Arc<str>, ArticleId>,
+    titles: Vec<Arc<str>>
+}
+
+impl TitleDict {
+    pub fn new() -> TitleDict {
+        TitleDict::default()
+    }
+
+    pub fn intern(&self, title: &str) -> ArticleId {
+        if let Some(id) = self.get(title) {
+            return id;
+        }
+        let mut inner = self.inner.write().unwrap();
+        // Someone else may have inserted it between the two locks
+        if let Some(id) = inner.ids.get(title) {
+            return *id;
+        }
+        let id = inner.titles.len() as ArticleId;
+        assert!(id < QUERY_ID_BASE, "title dictionary is full");
+        let shared: Arc<str> = Arc::from(title);
+        inner.titles.push(shared.clone());
+        inner.ids.insert(shared, id);
+        id
+    }
+
+    pub fn get(&self, title: &str) -> Option<ArticleId> {
+        self.inner.read().unwrap().ids.get(title).copied()
+    }
+
+    pub fn resolve(&self, id: ArticleId) -> Arc<str> {
+        self.inner.read().unwrap().titles[id as usize].clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().titles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/**
+ * Titles of a single query: the shared dictionary plus an overlay for query terms and keys
+ * the indexes only find while searching. Overlay ids start at QUERY_ID_BASE so they can't
+ * collide with shared ones, and go away with the query (the result cache keeps them around
+ * as long as it keeps the query).
+ */
+pub struct QueryTitles {
+    shared: Arc<TitleDict>,
+    local: RwLock<TitleDictInner>
+}
+
+impl QueryTitles {
+    pub fn new(shared: Arc<TitleDict>) -> QueryTitles {
+        QueryTitles{shared, local: RwLock::default()}
+    }
+
+    pub fn shared(&self) -> &TitleDict {
+        &self.shared
+    }
+
+    // Shared id if the title was interned at load time, a query local one otherwise
+    pub fn id(&self, title: &str) -> ArticleId {
+        if let Some(id) = self.get(title) {
+            return id;
+        }
+        let mut local = self.local.write().unwrap();
+        if let Some(id) = local.ids.get(title) {
+            return *id;
+        }
+        let id = QUERY_ID_BASE + local.titles.len() as ArticleId;
+        let title: Arc<str> = Arc::from(title);
+        local.titles.push(title.clone());
+        local.ids.insert(title, id);
+        id
+    }
+
+    pub fn get(&self, title: &str) -> Option<ArticleId> {
+        self.shared.get(title).or_else(|| self.local.read().unwrap().ids.get(title).copied())
+    }
+
+    pub fn resolve(&self, id: ArticleId) -> Arc<str> {
+        if id < QUERY_ID_BASE {
+            self.shared.resolve(id)
+        } else {
+            self.local.read().unwrap().titles[(id - QUERY_ID_BASE) as usize].clone()
+        }
+    }
+}
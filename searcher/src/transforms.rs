@@ -0,0 +1,170 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use super::lexicon::Lexicon;
+use super::substring::normalize;
+use super::titles::{ArticleId, TitleDict};
+
+const LETTERS: &str = "abcdefghijklmnopqrstuvwxyz";
+
+/**
+ * Kinds of word play transform, each is its own stage
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransformKind {
+    Reverse,
+    DropLetter,
+    AddLetter,
+    ChangeLetter,
+    Shift
+}
+
+impl TransformKind {
+    pub fn all() -> [TransformKind; 5] {
+        [TransformKind::Reverse, TransformKind::DropLetter, TransformKind::AddLetter, TransformKind::ChangeLetter, TransformKind::Shift]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransformKind::Reverse => "Reverse",
+            TransformKind::DropLetter => "DropLetter",
+            TransformKind::AddLetter => "AddLetter",
+            TransformKind::ChangeLetter => "ChangeLetter",
+            TransformKind::Shift => "Shift"
+        }
+    }
+}
+
+/**
+ * One applied transform, positions count letters from 0
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    Reverse,
+    Drop{at: usize, letter: char},
+    Add{at: usize, letter: char},
+    Change{at: usize, from: char, to: char},
+    // Every letter moved n places along the alphabet (Caesar shift)
+    Shift(u8)
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transform::Reverse => write!(f, "reversed"),
+            Transform::Drop{at, letter} => write!(f, "dropped {} at {}", letter, at + 1),
+            Transform::Add{at, letter} => write!(f, "added {} at {}", letter, at + 1),
+            Transform::Change{at, from, to} => write!(f, "changed {} to {} at {}", from, to, at + 1),
+            Transform::Shift(n) => write!(f, "shifted by {}", n)
+        }
+    }
+}
+
+fn shift_letter(c: char, n: u8) -> char {
+    if c.is_ascii_lowercase() {
+        (b'a' + (c as u8 - b'a' + n) % 26) as char
+    } else {
+        c
+    }
+}
+
+/**
+ * Every candidate spelling kind makes from the normalized letters of text, with how it was made.
+ * Candidates are not checked against any dictionary and may repeat.
+ */
+pub fn candidates(kind: TransformKind, text: &str) -> Vec<(String, Transform)> {
+    let letters: Vec<char> = normalize(text).chars().collect();
+    let mut results: Vec<(String, Transform)> = Vec::new();
+    if letters.is_empty() {
+        return results;
+    }
+    match kind {
+        TransformKind::Reverse => {
+            results.push((letters.iter().rev().collect(), Transform::Reverse));
+        },
+        TransformKind::DropLetter => {
+            for at in 0..letters.len() {
+                let mut dropped = letters.clone();
+                let letter = dropped.remove(at);
+                results.push((dropped.into_iter().collect(), Transform::Drop{at, letter}));
+            }
+        },
+        TransformKind::AddLetter => {
+            for at in 0..=letters.len() {
+                for letter in LETTERS.chars() {
+                    let mut added = letters.clone();
+                    added.insert(at, letter);
+                    results.push((added.into_iter().collect(), Transform::Add{at, letter}));
+                }
+            }
+        },
+        TransformKind::ChangeLetter => {
+            for at in 0..letters.len() {
+                for to in LETTERS.chars().filter(|to| *to != letters[at]) {
+                    let mut changed = letters.clone();
+                    changed[at] = to;
+                    results.push((changed.into_iter().collect(), Transform::Change{at, from: letters[at], to}));
+                }
+            }
+        },
+        TransformKind::Shift => {
+            for n in 1..26 {
+                results.push((letters.iter().map(|c| shift_letter(*c, n)).collect(), Transform::Shift(n)));
+            }
+        }
+    }
+    results
+}
+
+/**
+ * Normalized spelling -> every word or title spelled that way, so transforms only keep real words
+ */
+pub struct SpellingIndex {
+    spellings: HashMap<String, Vec<ArticleId>>
+}
+
+impl SpellingIndex {
+    pub fn new(lexicons: &[Arc<Lexicon>], titles: &TitleDict) -> SpellingIndex {
+        let mut spellings: HashMap<String, Vec<ArticleId>> = HashMap::new();
+        let mut seen: HashSet<ArticleId> = HashSet::new();
+        for lexicon in lexicons {
+            for word in lexicon.words() {
+                if !seen.insert(*word) {
+                    continue;
+                }
+                let spelling = normalize(&titles.resolve(*word));
+                if !spelling.is_empty() {
+                    spellings.entry(spelling).or_default().push(*word);
+                }
+            }
+        }
+        SpellingIndex{spellings}
+    }
+
+    /**
+     * Words and titles kind turns text into, other than text itself, with the first transform reaching each
+     */
+    pub fn search(&self, kind: TransformKind, text: &str) -> Vec<(ArticleId, Transform)> {
+        let original = normalize(text);
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut results: Vec<(ArticleId, Transform)> = Vec::new();
+        for (spelling, transform) in candidates(kind, text) {
+            if spelling == original || !seen.insert(spelling.clone()) {
+                continue;
+            }
+            if let Some(words) = self.spellings.get(&spelling) {
+                results.extend(words.iter().map(|word| (*word, transform)));
+            }
+        }
+        results
+    }
+
+    pub fn len(&self) -> usize {
+        self.spellings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spellings.is_empty()
+    }
+}
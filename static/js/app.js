@@ -120,6 +120,23 @@ function formatSingleChain(chain) {
         'WikiArticleStem': 'article [{2}] contains article [{1}] which stem-matched [{0}]',
         'WikiArticleExact': 'article [{2}] contains [{1}]',
         'WikiAllStem': 'article [{2}] has table/list item or article [{1}] which stem-matched [{0}]',
+        'Anagram': '[{2}] is an anagram of [{0}]',
+        'Anagram+1': '[{2}] is an anagram of [{0}] plus one letter',
+        'Anagram-1': '[{2}] is an anagram of [{0}] minus one letter',
+        'Phonetic': '[{2}] sounds like [{0}]',
+        'TrueHomophone': '[{2}] is pronounced like [{0}]',
+        'Rhyme': '[{2}] rhymes with [{0}]',
+        'Substring': '[{1}] hides [{0}]',
+        'Fuzzy1': 'article [{2}] has [{1}] which is within one letter of [{0}]',
+        'Fuzzy2': 'article [{2}] has [{1}] which is within two letters of [{0}]',
+        'Prefix': 'article [{2}] has [{1}] which starts with [{0}]',
+        'Pattern': 'article [{2}] has [{1}] which matches [{0}]',
+        'Reverse': '[{2}] is [{0}] {1}',
+        'DropLetter': '[{2}] is [{0}] {1}',
+        'AddLetter': '[{2}] is [{0}] {1}',
+        'ChangeLetter': '[{2}] is [{0}] {1}',
+        'Shift': '[{2}] is [{0}] {1}',
+        'PhraseCompletion': '[{0}] and [{2}] make [{1}]',
     };
     var explanations = [];
     var finalResult = "";
@@ -129,7 +146,7 @@ function formatSingleChain(chain) {
         let searchMatch = truncateSearchMatch(searchTerm, chain[i+2]);
         let searchResult = chain[i+3];
         finalResult = searchResult;
-        let template = EXPLANATIONS[stage];
+        let template = EXPLANATIONS[stage] || ('[{2}] (' + stage + ') from [{0}]');
         let explanationString = template.replace('{0}', searchTerm).replace('{1}', searchMatch).replace('{2}', searchResult);
         explanations.push(explanationString);
     }
@@ -139,26 +156,39 @@ function formatSingleChain(chain) {
     }
 }
 
-function formatResponse(responseArray) {
-    // General format is a list of dictionaries
-    // search_term => list of steps
+// Cursor, offset, limit and total of the page on display, for paging through the same results
+var currentPage = null;
+
+function formatResponse(responseObject, header) {
+    // General format is a page of results {cursor, total, offset, limit, results: [...]}
+    // each result is {association, score, covered: [terms], missing: [terms], chains: {search_term => list of steps}}
     // where each step is [StageName, search_term, search_match, result]
+    let responseArray = responseObject['results'];
     var pieces = [];
+    if (header) {
+        pieces.push(header);
+    }
+    let first = Math.min(responseObject['offset'] + 1, responseObject['total']);
+    let last = responseObject['offset'] + responseArray.length;
+    pieces.push("Results " + first + "-" + last + " of " + responseObject['total']);
+    if (responseObject['partial']) {
+        // The query ran out of time or size, these results are from the stages before
+        let partial = responseObject['partial'];
+        pieces.push("Stopped early in stage " + partial['position'] + " (" + partial['stage'] + "): " + partial['message'] + ", showing results up to the stage before");
+    }
     for (var i = 0; i < responseArray.length; i++) {
         let result = responseArray[i];
         var lines = []
-        var association = "";
-        for (var key in result) {
-            let processedChain = formatSingleChain(result[key]);
-            // All associatios will be the same
-            if (processedChain['finalAssociation']) {
-                association = processedChain['finalAssociation'];
-                lines.push("- " + processedChain['explanations'].join(' <= '));
-            } else {
-                lines.push("- nothing found for term: [" + key + "]");
-            }
+        for (var key in result['chains']) {
+            let processedChain = formatSingleChain(result['chains'][key]);
+            lines.push("- " + processedChain['explanations'].join(' <= '));
+        }
+        let total = result['covered'].length + result['missing'].length;
+        var coverage = result['covered'].length + "/" + total;
+        if (result['missing'].length > 0) {
+            coverage += " \u2014 missing: " + result['missing'].join(', ');
         }
-        var finalString = "[" + association + "]\n" + lines.join("\n");
+        var finalString = "[" + result['association'] + "] " + coverage + "\n" + lines.join("\n");
         pieces.push(finalString);
     }
     clearAndFillDisplayArea(pieces.join("\n\n"));
@@ -168,8 +198,15 @@ function handleResponse(responseText) {
     var responseObject = JSON.parse(responseText);
     let displayArea = document.getElementById("displayArea");
     if (responseObject['error']) {
-        clearAndFillDisplayArea(responseObject['error']);
+        // {code, message, stage} where stage is the stage at fault, if any
+        clearAndFillDisplayArea('Error (' + responseObject['error']['code'] + '): ' + responseObject['error']['message']);
     } else {
+        currentPage = {
+            'cursor': responseObject['cursor'],
+            'offset': responseObject['offset'],
+            'limit': responseObject['limit'],
+            'total': responseObject['total']
+        };
         formatResponse(responseObject)
     }
 }
@@ -178,6 +215,7 @@ function executeQuery() {
     let searchTermsArea = document.getElementById("searchTermsArea");
     let searchStagesArea = document.getElementById("searchStagesArea");
     let flavortextArea = document.getElementById("flavortextArea");
+    let minCoverageInput = document.getElementById("minCoverageInput");
 
     console.log(searchTermsArea.value);
     let payload = {
@@ -185,16 +223,110 @@ function executeQuery() {
         'terms': searchTermsArea.value.split(','),
         'flavortext': flavortextArea.value
     }
+    if (minCoverageInput.value !== '') {
+        payload['min_coverage'] = Number(minCoverageInput.value);
+    }
+    streamQuery(payload);
+}
+
+// Splits one Server-Sent Event into its name and data
+function parseEvent(text) {
+    var name = 'message';
+    var data = [];
+    text.split('\n').forEach(function(line) {
+        if (line.startsWith('event: ')) {
+            name = line.substring(7);
+        } else if (line.startsWith('data: ')) {
+            data.push(line.substring(6));
+        }
+    });
+    return {'name': name, 'data': data.join('\n')};
+}
+
+// progress holds one line per finished stage
+function handleEvent(event, progress) {
+    if (event['name'] === 'stage') {
+        let stage = JSON.parse(event['data']);
+        progress.push('Stage ' + stage['position'] + ' (' + stage['stage'] + ') done: ' + stage['size'] + ' associations in ' + stage['elapsed'].toFixed(1) + 's');
+        clearAndFillDisplayArea(progress.join('\n') + '\n\nStill searching...');
+    } else if (event['name'] === 'results') {
+        // Best associations so far, the next stage will replace them
+        let partial = JSON.parse(event['data']);
+        formatResponse({'offset': 0, 'total': partial['total'], 'results': partial['results']},
+                       progress.join('\n') + '\n\nStill searching, best after ' + partial['stage'] + ':');
+    } else if (event['name'] === 'done' || event['name'] === 'error') {
+        handleResponse(event['data']);
+    }
+}
+
+// Shows each stage finishing and early results while the query runs
+function streamQuery(payload) {
+    var progress = [];
+    showWaiting();
+    clearAndFillDisplayArea('');
+    fetch('/query/stream', {
+        'method': 'POST',
+        'headers': {'Content-Type': 'application/json'},
+        'body': JSON.stringify(payload)
+    }).then(function(response) {
+        if (response.headers.get('Content-Type') !== 'text/event-stream') {
+            // Bad queries are rejected before any stage runs
+            return response.text().then(handleResponse);
+        }
+        let reader = response.body.getReader();
+        let decoder = new TextDecoder();
+        var buffered = '';
+        function readEvents() {
+            return reader.read().then(function(chunk) {
+                if (chunk.done) {
+                    return;
+                }
+                buffered += decoder.decode(chunk.value, {'stream': true});
+                let events = buffered.split('\n\n');
+                // The last piece is an event that hasn't fully arrived yet
+                buffered = events.pop();
+                events.forEach(function(text) {
+                    handleEvent(parseEvent(text), progress);
+                });
+                return readEvents();
+            });
+        }
+        return readEvents();
+    }).catch(function(error) {
+        clearAndFillDisplayArea('Request failed: ' + error);
+    }).finally(hideWaiting);
+}
+
+// Pages reuse the results of the last query instead of searching again
+function nextPage() {
+    if (currentPage === null || currentPage['offset'] + currentPage['limit'] >= currentPage['total']) {
+        return;
+    }
+    sendQuery({'cursor': currentPage['cursor'], 'offset': currentPage['offset'] + currentPage['limit'], 'limit': currentPage['limit']});
+}
 
+function previousPage() {
+    if (currentPage === null || currentPage['offset'] === 0) {
+        return;
+    }
+    sendQuery({'cursor': currentPage['cursor'], 'offset': Math.max(currentPage['offset'] - currentPage['limit'], 0), 'limit': currentPage['limit']});
+}
+
+function sendQuery(payload) {
     var xhr = new XMLHttpRequest();
     xhr.open('POST', '/query', true);
     xhr.setRequestHeader("Content-Type", "application/json");
     
     xhr.onreadystatechange = function() { // Call a function when the state changes.
-        if (this.readyState === XMLHttpRequest.DONE && this.status === 200) {
+        if (this.readyState === XMLHttpRequest.DONE) {
             // Request finished. Do processing here.
             console.log(this);
-            handleResponse(this.responseText);
+            if (this.getResponseHeader("Content-Type") === "application/json") {
+                // Bad queries come back as 400 with an error message
+                handleResponse(this.responseText);
+            } else {
+                clearAndFillDisplayArea('Request failed with status ' + this.status);
+            }
             hideWaiting();
         }
     }